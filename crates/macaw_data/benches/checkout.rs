@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use macaw_data::{prelude::*, RecordId};
+
+const RECORD_COUNT: usize = 1000;
+
+#[derive(Clone, Debug, Default)]
+struct Transform {
+    x: i32,
+    y: i32,
+}
+
+impl Record for Transform {
+    fn type_name() -> &'static str {
+        "Transform"
+    }
+
+    fn proto_update(&self, old: &Transform, new: &Transform) -> Transform {
+        Transform {
+            x: *proto_update_field(&self.x, &old.x, &new.x),
+            y: *proto_update_field(&self.y, &old.y, &new.y),
+        }
+    }
+}
+
+fn checkout(c: &mut Criterion) {
+    let library = Library::default();
+    library.register::<Transform>();
+    let ids = library.with(|catalog: &Catalog<Transform>| {
+        (0..RECORD_COUNT)
+            .map(|_| catalog.create(Transform::default()))
+            .collect::<Vec<RecordId<Transform>>>()
+    });
+
+    c.bench_function("checkout_per_read", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| library.checkout::<Transform>().get(*id).x as i64)
+                .sum::<i64>()
+        })
+    });
+
+    c.bench_function("with_once", |b| {
+        b.iter(|| {
+            library.with(|catalog: &Catalog<Transform>| {
+                ids.iter().map(|id| catalog.get(*id).x as i64).sum::<i64>()
+            })
+        })
+    });
+}
+
+criterion_group!(benches, checkout);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+// Compare `cargo bench --bench commit_loop` with and without `--features wrapper_pool`. Commits
+// go through `lock_records` since `lock` keeps every version it hands out alive. So far the pool
+// hasn't paid off here: the commit loop ran about 10% slower with it than with plain allocation.
+use criterion::{criterion_group, criterion_main, Criterion};
+use macaw_data::prelude::*;
+
+const COMMIT_COUNT: i32 = 1000;
+
+#[derive(Clone, Debug, Default)]
+struct Transform {
+    x: i32,
+    y: i32,
+}
+
+impl Record for Transform {
+    fn type_name() -> &'static str {
+        "Transform"
+    }
+
+    fn proto_update(&self, old: &Transform, new: &Transform) -> Transform {
+        Transform {
+            x: *proto_update_field(&self.x, &old.x, &new.x),
+            y: *proto_update_field(&self.y, &old.y, &new.y),
+        }
+    }
+}
+
+fn commit_loop(c: &mut Criterion) {
+    let library = Library::default();
+    library.register::<Transform>();
+    let catalog = library.checkout::<Transform>();
+    let proto_id = catalog.create(Transform::default());
+    for _ in 0..4 {
+        catalog.create_from_prototype(proto_id);
+    }
+
+    c.bench_function("commit_loop", |b| {
+        b.iter(|| {
+            for x in 0..COMMIT_COUNT {
+                catalog
+                    .lock_records()
+                    .commit(proto_id, Transform { x, y: 0 });
+            }
+            catalog.compact_change_log(catalog.watermark());
+        })
+    });
+}
+
+criterion_group!(benches, commit_loop);
+criterion_main!(benches);
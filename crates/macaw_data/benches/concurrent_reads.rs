@@ -0,0 +1,90 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use macaw_data::{prelude::*, RecordId};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+const RECORD_COUNT: usize = 1000;
+const READER_COUNT: usize = 4;
+
+#[derive(Clone, Debug, Default)]
+struct Transform {
+    x: i32,
+    y: i32,
+}
+
+impl Record for Transform {
+    fn type_name() -> &'static str {
+        "Transform"
+    }
+
+    fn proto_update(&self, old: &Transform, new: &Transform) -> Transform {
+        Transform {
+            x: *proto_update_field(&self.x, &old.x, &new.x),
+            y: *proto_update_field(&self.y, &old.y, &new.y),
+        }
+    }
+}
+
+fn read_all(library: &Library, ids: &[RecordId<Transform>]) -> i64 {
+    thread::scope(|scope| {
+        let readers = (0..READER_COUNT)
+            .map(|_| {
+                scope.spawn(|| {
+                    let catalog = library.checkout::<Transform>();
+                    ids.iter().map(|id| catalog.get(*id).x as i64).sum::<i64>()
+                })
+            })
+            .collect::<Vec<_>>();
+        readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .sum()
+    })
+}
+
+fn concurrent_reads(c: &mut Criterion) {
+    let library = Library::default();
+    library.register::<Transform>();
+    let catalog = library.checkout::<Transform>();
+    let ids = (0..RECORD_COUNT)
+        .map(|_| catalog.create(Transform::default()))
+        .collect::<Vec<_>>();
+
+    c.bench_function("reads", |b| b.iter(|| read_all(&library, &ids)));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let library = library.clone();
+        let ids = ids.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let catalog = library.checkout::<Transform>();
+            let mut x = 0;
+            while !stop.load(Ordering::Relaxed) {
+                for id in &ids {
+                    catalog
+                        .lock(*id)
+                        .unwrap()
+                        .mutate(|transform| transform.x = x)
+                        .unwrap();
+                }
+                x += 1;
+            }
+        })
+    };
+
+    c.bench_function("reads_while_writing", |b| {
+        b.iter(|| read_all(&library, &ids))
+    });
+
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+criterion_group!(benches, concurrent_reads);
+criterion_main!(benches);
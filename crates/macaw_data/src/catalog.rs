@@ -1,10 +1,24 @@
+#[cfg(feature = "serde")]
+use crate::export::{DeserializeChange, LoadError, WriteAheadLog};
 use crate::{
-    library::Sequencer,
-    record::{Locked, Record, RecordId, RecordWrapper},
+    change_log::{AnyChange, CatalogIterator, Change, HistoryPolicy, NetChange, Watermark},
+    index::RecordIndex,
+    library::{AnyCatalogState, ChangeListeners, Library, Sequencer, Subscription},
+    record::{
+        field_mask, EditError, IdError, Locked, Record, RecordId, RecordRef, RecordWrapper,
+        ReparentError, Shutdown, ValidationError,
+    },
+    sync::{thread, Condvar, Mutex, MutexGuard, ThreadId},
 };
+use arc_swap::{ArcSwap, ArcSwapOption};
 use std::{
-    fmt::Debug,
-    sync::{Arc, Condvar, Mutex, MutexGuard},
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Debug, Write},
+    mem,
+    ops::Deref,
+    sync::{atomic::AtomicU64, atomic::Ordering, Arc, Weak},
+    time::{Duration, Instant},
 };
 
 #[derive(Default)]
@@ -15,26 +29,259 @@ where
     pub(crate) state: Arc<CatalogState<R>>,
     pub(crate) reads: Mutex<Vec<Arc<RecordWrapper<R>>>>,
     pub(crate) sequencer: Sequencer,
+    pub(crate) change_listeners: ChangeListeners,
+    #[cfg(feature = "serde")]
+    pub(crate) write_ahead_log: WriteAheadLog,
+    pub(crate) generation: u64,
+    pub(crate) library: Library,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub(crate) struct CatalogState<R>
 where
     R: Record,
 {
+    pub(crate) generation: AtomicU64,
     pub(crate) locks_cv: Condvar,
+    pub(crate) published: Arc<PublishedRecords<R>>,
+    pub(crate) commit_rejected_listeners: CommitRejectedListeners<R>,
+    pub(crate) validator: CommitValidator<R>,
+    pub(crate) commit_observers: CommitObservers<R>,
+    #[cfg(feature = "serde")]
+    pub(crate) serialize_change: Option<fn(&ChangeRecord<R>) -> serde_json::Value>,
+    #[cfg(feature = "serde")]
+    pub(crate) deserialize_change: Option<DeserializeChange<R>>,
+    #[cfg(feature = "serde")]
+    pub(crate) serialize_default_record: Option<fn() -> serde_json::Value>,
     pub(crate) inner: Mutex<CatalogStateInner<R>>,
 }
 
+impl<R> CatalogState<R>
+where
+    R: Record,
+{
+    pub(crate) fn from_inner(generation: u64, mut inner: CatalogStateInner<R>) -> Self {
+        let published = Arc::from(PublishedRecords::from_records(&inner.records));
+        inner.published = published.clone();
+        CatalogState {
+            generation: AtomicU64::new(generation),
+            locks_cv: Default::default(),
+            published,
+            commit_rejected_listeners: Default::default(),
+            validator: Default::default(),
+            commit_observers: Default::default(),
+            #[cfg(feature = "serde")]
+            serialize_change: None,
+            #[cfg(feature = "serde")]
+            deserialize_change: None,
+            #[cfg(feature = "serde")]
+            serialize_default_record: None,
+            inner: Mutex::from(inner),
+        }
+    }
+}
+
+impl<R> Default for CatalogState<R>
+where
+    R: Record,
+{
+    fn default() -> Self {
+        CatalogState::from_inner(Default::default(), Default::default())
+    }
+}
+
+// A record and the ids it refers to that don't exist, see `Catalog::find_dangling`.
+pub type DanglingReferences<R> = (RecordId<R>, Vec<(&'static str, RecordId)>);
+
+type CommitRejectedListener<R> = Arc<dyn Fn(RecordId<R>, ValidationError) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct CommitRejectedListeners<R> {
+    listeners: Mutex<Vec<CommitRejectedListener<R>>>,
+}
+
+impl<R> CommitRejectedListeners<R> {
+    fn notify(&self, id: RecordId<R>, error: &ValidationError) {
+        let listeners = self.listeners.lock().unwrap().clone();
+        for listener in listeners {
+            listener(id, error.clone());
+        }
+    }
+}
+
+impl<R> Debug for CommitRejectedListeners<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitRejectedListeners")
+            .field("len", &self.listeners.lock().unwrap().len())
+            .finish()
+    }
+}
+
+type CommitObserver<R> = Arc<dyn Fn(RecordId<R>, Option<&R>, &R) + Send + Sync>;
+
+pub(crate) struct CommitObservers<R> {
+    observers: Mutex<Vec<CommitObserver<R>>>,
+}
+
+impl<R> Default for CommitObservers<R> {
+    fn default() -> Self {
+        CommitObservers {
+            observers: Default::default(),
+        }
+    }
+}
+
+impl<R> CommitObservers<R>
+where
+    R: Record,
+{
+    fn notify(&self, commits: &Commits<R>) {
+        if commits.records.is_empty() {
+            return;
+        }
+        let observers = self.observers.lock().unwrap().clone();
+        for (id, old_record, new_record) in &commits.records {
+            for observer in &observers {
+                observer(*id, Some(&old_record.inner), &new_record.inner);
+            }
+        }
+    }
+}
+
+impl<R> Debug for CommitObservers<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitObservers")
+            .field("len", &self.observers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+type CommittedRecord<R> = (RecordId<R>, Arc<RecordWrapper<R>>, Arc<RecordWrapper<R>>);
+
+// What a commit and its cascade wrote, kept until the state is released so listeners and
+// observers can read the catalog.
+pub(crate) struct Commits<R>
+where
+    R: Record,
+{
+    changes: Vec<(RecordId<R>, u64)>,
+    records: Vec<CommittedRecord<R>>,
+}
+
+impl<R> Default for Commits<R>
+where
+    R: Record,
+{
+    fn default() -> Self {
+        Commits {
+            changes: Default::default(),
+            records: Default::default(),
+        }
+    }
+}
+
+type ValidatorFn<R> = Arc<dyn Fn(&R) -> Result<(), String> + Send + Sync>;
+
+// Set with `Catalog::set_validator`. Checked after `Record::validate`, on the committed record
+// and on every instance value its prototype cascade would write.
+pub(crate) struct CommitValidator<R> {
+    validator: Mutex<Option<ValidatorFn<R>>>,
+}
+
+impl<R> Default for CommitValidator<R> {
+    fn default() -> Self {
+        CommitValidator {
+            validator: Default::default(),
+        }
+    }
+}
+
+impl<R> CommitValidator<R>
+where
+    R: Record,
+{
+    fn check(&self, record: &R) -> Result<(), ValidationError> {
+        record.validate()?;
+        let validator = self.validator.lock().unwrap().clone();
+        match validator {
+            Some(validator) => validator(record).map_err(ValidationError),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R> Debug for CommitValidator<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitValidator")
+            .field("is_set", &self.validator.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+// Lock-free mirror of `CatalogStateInner::records` for readers. Writers update it while
+// holding the state lock, so each slot is always the latest committed wrapper, but a reader
+// may observe a cascade part way through.
+#[derive(Debug, Default)]
+pub(crate) struct PublishedRecords<R>
+where
+    R: Record,
+{
+    slots: ArcSwap<Vec<Arc<ArcSwapOption<RecordWrapper<R>>>>>,
+}
+
+impl<R> PublishedRecords<R>
+where
+    R: Record,
+{
+    fn from_records(records: &[Option<Arc<RecordWrapper<R>>>]) -> Self {
+        let slots = records
+            .iter()
+            .map(|record| Arc::from(ArcSwapOption::new(record.clone())))
+            .collect::<Vec<_>>();
+        PublishedRecords {
+            slots: ArcSwap::from_pointee(slots),
+        }
+    }
+
+    pub(crate) fn load(&self, id: RecordId<R>) -> Option<Arc<RecordWrapper<R>>> {
+        self.slots
+            .load()
+            .get(id.0)?
+            .load_full()
+            .filter(|record_wrapper| record_wrapper.generation == id.1)
+    }
+
+    // Only called with the state lock held, which serializes growing the slot list.
+    fn store(&self, id: RecordId<R>, record_wrapper: Option<Arc<RecordWrapper<R>>>) {
+        let slots = self.slots.load_full();
+        if slots.len() <= id.0 {
+            let mut grown = Vec::clone(&slots);
+            grown.resize_with((id.0 + 1).max(slots.len() * 2), Default::default);
+            self.slots.store(Arc::from(grown));
+        }
+        self.slots.load()[id.0].store(record_wrapper);
+    }
+
+    // Swaps in every slot of `other` at once.
+    fn store_all(&self, other: &PublishedRecords<R>) {
+        self.slots.store(other.slots.load_full());
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ChangeRecord<R>
 where
     R: Record,
 {
-    pub(crate) record_id: RecordId,
+    pub(crate) record_id: RecordId<R>,
+    pub(crate) cause: Option<RecordId<R>>,
     pub(crate) lsn: u64,
+    pub(crate) transaction: Option<u64>,
+    // Only used by `HistoryPolicy::MaxAge`. Changes loaded from elsewhere get the time they
+    // were loaded at.
+    pub(crate) committed_at: Instant,
     pub(crate) old_record: Option<Arc<RecordWrapper<R>>>,
-    pub(crate) new_record: Arc<RecordWrapper<R>>,
+    pub(crate) new_record: Option<Arc<RecordWrapper<R>>>,
 }
 
 #[derive(Debug, Default)]
@@ -42,139 +289,4463 @@ pub(crate) struct CatalogStateInner<R>
 where
     R: Record,
 {
-    pub(crate) locks: Vec<bool>,
+    pub(crate) locks: Vec<Option<ThreadId>>,
+    pub(crate) exclusive: Option<ThreadId>,
     pub(crate) change_log: Vec<ChangeRecord<R>>,
-    records: Vec<Arc<RecordWrapper<R>>>,
+    pub(crate) change_log_start: usize,
+    pub(crate) history_policy: HistoryPolicy,
+    records: Vec<Option<Arc<RecordWrapper<R>>>>,
+    history: Vec<Option<Arc<RecordWrapper<R>>>>,
+    versions: Vec<u64>,
+    inherited: Vec<bool>,
+    tombstones: Vec<bool>,
+    // Unlike the other per-slot vectors it's never truncated, so a slot freed by `delete_swap`
+    // still moves on to a new generation when it's filled again.
+    generations: Vec<u32>,
+    #[cfg(feature = "wrapper_pool")]
+    wrapper_pool: std::collections::VecDeque<Arc<RecordWrapper<R>>>,
+    cascade_histogram: BTreeMap<usize, u64>,
+    published: Arc<PublishedRecords<R>>,
+    transaction: Option<u64>,
+    shutdown: bool,
+    // Dropped `IndexHandle`s are pruned on the next update.
+    indexes: Vec<Weak<dyn RecordIndex<R>>>,
 }
 
-impl<R> Catalog<R>
+impl<R> CatalogStateInner<R>
 where
     R: Record,
 {
-    pub fn create(&self, record: R) -> RecordId {
-        self.create_internal(RecordWrapper {
-            prototype_id: None,
-            prototype_instances: Default::default(),
-            inner: record,
-        })
+    pub(crate) fn record(&self, id: RecordId<R>) -> &Arc<RecordWrapper<R>> {
+        self.live_record(id)
+            .unwrap_or_else(|| panic!("{}", self.missing_record_message(id)))
     }
 
-    pub fn create_from_prototype(&self, prototype_id: RecordId) -> RecordId {
-        let prototype_wrapper = self.get_internal(prototype_id, true);
-        let instance = prototype_wrapper.inner.clone();
-        let instance_id = self.create_internal(RecordWrapper {
-            prototype_id: Some(prototype_id),
-            prototype_instances: Default::default(),
-            inner: instance,
-        });
-
-        prototype_wrapper
-            .prototype_instances
-            .lock()
-            .unwrap()
-            .insert(instance_id);
+    fn live_record(&self, id: RecordId<R>) -> Option<&Arc<RecordWrapper<R>>> {
+        live_record(&self.records, id)
+    }
 
-        self.unlock(prototype_id);
-        instance_id
+    pub(crate) fn add_index(&mut self, index: Arc<dyn RecordIndex<R>>) {
+        for (id, record) in iter_records(&self.records) {
+            index.update(id, Some(record));
+        }
+        self.indexes.push(Arc::downgrade(&index));
     }
 
-    fn create_internal(&self, record_wrapper: RecordWrapper<R>) -> RecordId {
-        let mut state = self.state.inner.lock().unwrap();
-        let id = state.records.len();
-        let record_wrapper = Arc::from(record_wrapper);
-        state.records.push(record_wrapper.clone());
-        state.locks.push(false);
-        let record_id = RecordId(id);
-        self.write_change_log(record_id, None, record_wrapper, state);
-        record_id
+    fn update_indexes(&mut self, id: RecordId<R>, record: Option<&R>) {
+        self.indexes.retain(|index| match index.upgrade() {
+            Some(index) => {
+                index.update(id, record);
+                true
+            }
+            None => false,
+        });
     }
 
-    pub fn get(&self, id: RecordId) -> &R {
-        self.unwrap_record_wrapper(&self.get_internal(id, false))
+    // The generation the record in slot `index` has, or the one the next record created there
+    // will get.
+    fn generation(&self, index: usize) -> u32 {
+        self.generations.get(index).copied().unwrap_or_default()
     }
 
-    pub fn lock(&self, id: RecordId) -> Locked<R> {
-        Locked {
-            id,
-            value: self.unwrap_record_wrapper(&self.get_internal(id, true)),
-            catalog: self,
+    // The thread holding `id`'s lock. Unknown ids, and stale ones whose slot has moved on to
+    // another record, are never locked.
+    fn lock_owner(&self, id: RecordId<R>) -> Option<ThreadId> {
+        match self.generation(id.0) == id.1 {
+            true => self.locks.get(id.0).copied().flatten(),
+            false => None,
         }
     }
 
-    fn get_internal(&self, id: RecordId, lock: bool) -> Arc<RecordWrapper<R>> {
-        let mut state = self.state.inner.lock().unwrap();
-        if lock {
-            state = self
-                .state
-                .locks_cv
-                .wait_while(state, |library| library.locks[id.0])
-                .unwrap();
-            state.locks[id.0] = true;
+    fn missing_record_message(&self, id: RecordId<R>) -> String {
+        if self.tombstones.get(id.0) == Some(&true) || id.1 < self.generation(id.0) {
+            format!("{} record {:?} was deleted", R::type_name(), id)
+        } else {
+            format!("{} record {:?} does not exist", R::type_name(), id)
         }
+    }
 
-        let record = &state.records[id.0];
-        record.clone()
+    fn is_exclusive_to_other_thread(&self) -> bool {
+        self.exclusive.is_some() && self.exclusive != Some(thread::current().id())
     }
 
-    fn unwrap_record_wrapper(&self, record_wrapper: &Arc<RecordWrapper<R>>) -> &R {
-        self.reads.lock().unwrap().push(record_wrapper.clone());
-        unsafe {
-            let record_ref = Arc::as_ptr(record_wrapper).as_ref().unwrap();
-            &<&RecordWrapper<R>>::clone(&record_ref).inner
+    fn is_cascade_free(&self, id: RecordId<R>) -> bool {
+        if self.is_exclusive_to_other_thread() {
+            return false;
+        }
+
+        let current_thread = thread::current().id();
+        let mut pending_ids = vec![id];
+        while let Some(prototype_id) = pending_ids.pop() {
+            let prototype_wrapper = match self.live_record(prototype_id) {
+                Some(prototype_wrapper) => prototype_wrapper,
+                None => return false,
+            };
+            if matches!(self.locks[prototype_id.0], Some(owner) if owner != current_thread) {
+                return false;
+            }
+            for instance_id in prototype_wrapper.prototype_instances.lock().unwrap().iter() {
+                if let Some(instance_wrapper) = self.live_record(*instance_id) {
+                    if instance_wrapper.prototype_id == Some(prototype_id) {
+                        pending_ids.push(*instance_id);
+                    }
+                }
+            }
         }
+        true
     }
 
-    pub fn unlock(&self, id: RecordId) {
-        let mut state = self.state.inner.lock().unwrap();
-        state.locks[id.0] = false;
-        self.state.locks_cv.notify_all();
+    fn insert(&mut self, id: RecordId<R>, record_wrapper: Arc<RecordWrapper<R>>) {
+        self.reserve(id);
+        self.set_record(id, Some(record_wrapper));
     }
 
-    pub fn commit(&self, locked: &Locked<R>, new_record: R) {
-        let old_record = self.get_internal(locked.id, false);
-        self.commit_internal(locked.id, old_record, new_record)
+    fn reserve(&mut self, id: RecordId<R>) {
+        if self.records.len() <= id.0 {
+            self.records.resize(id.0 + 1, None);
+            self.locks.resize(id.0 + 1, None);
+            self.history.resize(id.0 + 1, None);
+            self.versions.resize(id.0 + 1, 0);
+            self.inherited.resize(id.0 + 1, false);
+            self.tombstones.resize(id.0 + 1, false);
+        }
+        if self.generations.len() <= id.0 {
+            self.generations.resize(id.0 + 1, 0);
+        }
     }
 
-    fn commit_internal(&self, id: RecordId, old_record: Arc<RecordWrapper<R>>, new_record: R) {
-        let old_prototype_instances = old_record.prototype_instances.lock().unwrap();
-        let new_instance = Arc::from(RecordWrapper {
-            prototype_id: old_record.prototype_id,
-            prototype_instances: Mutex::from(old_prototype_instances.clone()),
-            inner: new_record,
+    // Applies a change from another log as-is, relinking the record to its prototype. Nothing
+    // cascades since the log already holds every instance's own change.
+    #[cfg(feature = "serde")]
+    pub(crate) fn replay(&mut self, change_record: ChangeRecord<R>) {
+        let id = change_record.record_id;
+        self.reserve(id);
+        let record_wrapper = change_record.new_record.as_ref().map(|new_record| {
+            let instance_ids = match &self.records[id.0] {
+                Some(old_record) => old_record.prototype_instances.lock().unwrap().clone(),
+                None => Default::default(),
+            };
+            Arc::from(RecordWrapper {
+                prototype_id: new_record.prototype_id,
+                prototype_instances: Mutex::from(instance_ids),
+                override_mask: new_record.override_mask,
+                generation: id.1,
+                inner: new_record.inner.clone(),
+            })
         });
+        let old_prototype_id = self.live_record(id).and_then(|w| w.prototype_id);
+        let new_prototype_id = record_wrapper.as_ref().and_then(|w| w.prototype_id);
+        if let Some(prototype_id) =
+            old_prototype_id.filter(|_| old_prototype_id != new_prototype_id)
+        {
+            if let Some(prototype_wrapper) = self.own_record(prototype_id) {
+                prototype_wrapper
+                    .prototype_instances
+                    .lock()
+                    .unwrap()
+                    .remove(&id);
+            }
+        }
+        if let Some(prototype_id) = new_prototype_id {
+            if let Some(prototype_wrapper) = self.own_record(prototype_id) {
+                prototype_wrapper
+                    .prototype_instances
+                    .lock()
+                    .unwrap()
+                    .insert(id);
+            }
+        }
+        self.update_indexes(id, record_wrapper.as_ref().map(|w| &w.inner));
+        self.set_record(id, record_wrapper);
+        self.tombstones[id.0] = change_record.new_record.is_none();
+        self.history[id.0] = change_record.new_record.clone();
+        self.versions[id.0] = change_record.lsn;
+        self.change_log.push(change_record);
+        self.apply_history_policy();
+    }
 
-        let mut state_inner = self.state.inner.lock().unwrap();
-        state_inner.records[id.0] = new_instance.clone();
-        self.write_change_log(
-            id,
-            Some(old_record.clone()),
-            new_instance.clone(),
-            state_inner,
-        );
+    // Drops every change before `up_to`, returning how many were dropped.
+    pub(crate) fn compact_change_log(&mut self, up_to: Watermark) -> usize {
+        let removed = up_to
+            .0
+            .saturating_sub(self.change_log_start)
+            .min(self.change_log.len());
+        self.change_log.drain(..removed);
+        self.change_log_start += removed;
+        removed
+    }
 
-        for instance_id in old_prototype_instances.iter() {
-            let instance_wrapper = self.get_internal(*instance_id, true);
-            let new_instance = instance_wrapper
-                .inner
-                .proto_update(&old_record.inner, &new_instance.inner);
-            self.commit_internal(*instance_id, instance_wrapper, new_instance);
-            self.unlock(*instance_id);
+    pub(crate) fn apply_history_policy(&mut self) {
+        let kept = match self.history_policy {
+            HistoryPolicy::Unlimited => return,
+            HistoryPolicy::MaxEntries(max_entries) => max_entries.min(self.change_log.len()),
+            HistoryPolicy::MaxAge(max_age) => {
+                let now = Instant::now();
+                self.change_log.len()
+                    - self
+                        .change_log
+                        .iter()
+                        .take_while(|change_record| {
+                            now.duration_since(change_record.committed_at) >= max_age
+                        })
+                        .count()
+            }
+        };
+        let end = self.change_log_start + self.change_log.len();
+        self.compact_change_log(Watermark(end - kept));
+    }
+
+    fn truncate(&mut self, len: usize) {
+        for index in len..self.records.len() {
+            self.published.store(RecordId::new(index), None);
         }
+        self.records.truncate(len);
+        self.locks.truncate(len);
+        self.history.truncate(len);
+        self.versions.truncate(len);
+        self.inherited.truncate(len);
+        self.tombstones.truncate(len);
     }
 
-    fn write_change_log(
+    // Emptying a slot moves it on to the next generation.
+    fn set_record(&mut self, id: RecordId<R>, record_wrapper: Option<Arc<RecordWrapper<R>>>) {
+        self.generations[id.0] = match &record_wrapper {
+            Some(record_wrapper) => record_wrapper.generation,
+            None => id.1.wrapping_add(1),
+        };
+        self.published.store(id, record_wrapper.clone());
+        self.tombstones[id.0] = false;
+        let _replaced = mem::replace(&mut self.records[id.0], record_wrapper);
+        self.inherited[id.0] = false;
+        #[cfg(feature = "wrapper_pool")]
+        if let Some(replaced) = _replaced {
+            if self.wrapper_pool.len() < WRAPPER_POOL_CAPACITY {
+                self.wrapper_pool.push_back(replaced);
+            }
+        }
+    }
+
+    // With the `wrapper_pool` feature, replaced wrappers are kept around and their allocation
+    // is reused once nothing else holds them anymore.
+    fn new_wrapper(
+        &mut self,
+        prototype_id: Option<RecordId<R>>,
+        prototype_instances: HashSet<RecordId<R>>,
+        override_mask: u64,
+        generation: u32,
+        inner: R,
+    ) -> Arc<RecordWrapper<R>> {
+        // Only the oldest pooled wrapper is checked, it's the likeliest to have no readers left.
+        #[cfg(feature = "wrapper_pool")]
+        if let Some(mut pooled) = self.wrapper_pool.pop_front() {
+            match Arc::get_mut(&mut pooled) {
+                Some(record_wrapper) => {
+                    record_wrapper.prototype_id = prototype_id;
+                    *record_wrapper.prototype_instances.get_mut().unwrap() = prototype_instances;
+                    record_wrapper.override_mask = override_mask;
+                    record_wrapper.generation = generation;
+                    record_wrapper.inner = inner;
+                    return pooled;
+                }
+                None => self.wrapper_pool.push_back(pooled),
+            }
+        }
+        Arc::from(RecordWrapper {
+            prototype_id,
+            prototype_instances: Mutex::from(prototype_instances),
+            override_mask,
+            generation,
+            inner,
+        })
+    }
+
+    // Wrappers inherited from a branch's parent are shared with it, so they're copied before
+    // their prototype links are mutated in place.
+    fn own_record(&mut self, id: RecordId<R>) -> Option<&Arc<RecordWrapper<R>>> {
+        let record_wrapper = self.live_record(id)?;
+        if self.inherited[id.0] {
+            let record_wrapper = Arc::from(RecordWrapper {
+                prototype_id: record_wrapper.prototype_id,
+                prototype_instances: Mutex::from(
+                    record_wrapper.prototype_instances.lock().unwrap().clone(),
+                ),
+                override_mask: record_wrapper.override_mask,
+                generation: record_wrapper.generation,
+                inner: record_wrapper.inner.clone(),
+            });
+            self.set_record(id, Some(record_wrapper));
+        }
+        self.records[id.0].as_ref()
+    }
+}
+
+impl<R> AnyCatalogState for CatalogState<R>
+where
+    R: Record,
+{
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn record_count(&self) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .filter(|record| record.is_some())
+            .count()
+    }
+
+    fn contains(&self, id: RecordId) -> bool {
+        self.published.load(id.typed()).is_some()
+    }
+
+    fn retire(&self) {
+        self.generation.store(RETIRED_GENERATION, Ordering::SeqCst);
+    }
+
+    fn changes_since(&self, watermark: Watermark) -> (Vec<AnyChange>, Watermark) {
+        let state_inner = self.inner.lock().unwrap();
+        let start = watermark.0.saturating_sub(state_inner.change_log_start);
+        let changes = state_inner
+            .change_log
+            .iter()
+            .skip(start)
+            .map(AnyChange::from_change_record)
+            .collect();
+        let end = state_inner.change_log_start + state_inner.change_log.len();
+        (changes, Watermark(end))
+    }
+
+    fn changes_between(&self, start_point: Watermark, end_point: Watermark) -> Vec<AnyChange> {
+        let state_inner = self.inner.lock().unwrap();
+        let start = start_point.0.max(state_inner.change_log_start);
+        state_inner
+            .change_log
+            .iter()
+            .skip(start - state_inner.change_log_start)
+            .take(end_point.0.saturating_sub(start))
+            .map(AnyChange::from_change_record)
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_serializable(&self) -> bool {
+        self.serialize_change.is_some()
+            && self.deserialize_change.is_some()
+            && self.serialize_default_record.is_some()
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialize_default_record(&self) -> serde_json::Value {
+        self.serialize_default_record
+            .map(|serialize_default_record| serialize_default_record())
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "serde")]
+    fn export_changes(&self) -> Vec<serde_json::Value> {
+        crate::export::export_changes(self)
+    }
+
+    #[cfg(feature = "serde")]
+    fn import_changes(
         &self,
-        id: RecordId,
-        old_record: Option<Arc<RecordWrapper<R>>>,
-        new_record: Arc<RecordWrapper<R>>,
-        mut state_inner: MutexGuard<CatalogStateInner<R>>,
-    ) {
-        let lsn = self.sequencer.next();
-        state_inner.change_log.push(ChangeRecord {
-            record_id: id,
-            old_record,
-            new_record,
-            lsn,
+        changes: Vec<serde_json::Value>,
+        sequencer: &Sequencer,
+    ) -> Result<(), LoadError> {
+        crate::export::import_changes(self, changes, sequencer)
+    }
+}
+
+pub(crate) const RETIRED_GENERATION: u64 = 0;
+
+#[cfg(feature = "wrapper_pool")]
+const WRAPPER_POOL_CAPACITY: usize = 64;
+
+// `None` when `id`'s slot is empty or has moved on to a later generation.
+fn live_record<R>(
+    records: &[Option<Arc<RecordWrapper<R>>>],
+    id: RecordId<R>,
+) -> Option<&Arc<RecordWrapper<R>>>
+where
+    R: Record,
+{
+    records
+        .get(id.0)?
+        .as_ref()
+        .filter(|record_wrapper| record_wrapper.generation == id.1)
+}
+
+// Whether `id` is `ancestor_id` or derives from it, following `prototype_of` upwards.
+fn descends_from<R, F>(id: RecordId<R>, ancestor_id: RecordId<R>, prototype_of: F) -> bool
+where
+    F: Fn(RecordId<R>) -> Option<RecordId<R>>,
+{
+    let mut current_id = Some(id);
+    while let Some(id) = current_id {
+        if id == ancestor_id {
+            return true;
+        }
+        current_id = prototype_of(id);
+    }
+    false
+}
+
+fn iter_records<R>(
+    records: &[Option<Arc<RecordWrapper<R>>>],
+) -> impl Iterator<Item = (RecordId<R>, &R)>
+where
+    R: Record,
+{
+    records.iter().enumerate().filter_map(|(index, record)| {
+        let record_wrapper = record.as_ref()?;
+        Some((record_wrapper.id_at(index), &record_wrapper.inner))
+    })
+}
+
+// An owned view of the records as they were when `Catalog::snapshot` was called. Reading
+// from it doesn't go through the catalog, so later commits and deletes don't show up.
+#[derive(Clone)]
+pub struct Snapshot<R>
+where
+    R: Record,
+{
+    records: Vec<Option<Arc<RecordWrapper<R>>>>,
+}
+
+impl<R> Snapshot<R>
+where
+    R: Record,
+{
+    pub fn get(&self, id: RecordId<R>) -> Option<&R> {
+        Some(&live_record(&self.records, id)?.inner)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RecordId<R>, &R)> {
+        iter_records(&self.records)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records
+            .iter()
+            .filter(|record| record.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A checkout that can only read, from `Library::checkout_readonly`, for systems that must
+/// never write. It has no way to create, lock, commit or delete:
+///
+/// ```compile_fail
+/// # use macaw_data::prelude::*;
+/// # #[derive(Clone, Debug, Default)]
+/// # struct Note;
+/// # impl Record for Note {
+/// #     fn type_name() -> &'static str {
+/// #         "Note"
+/// #     }
+/// #     fn proto_update(&self, _old: &Note, new: &Note) -> Note {
+/// #         new.clone()
+/// #     }
+/// # }
+/// let library = Library::default();
+/// library.register::<Note>();
+/// library.checkout_readonly::<Note>().create(Note);
+/// ```
+pub struct ReadCatalog<R>
+where
+    R: Record,
+{
+    pub(crate) catalog: Catalog<R>,
+}
+
+impl<R> ReadCatalog<R>
+where
+    R: Record,
+{
+    pub fn get(&self, id: RecordId<R>) -> RecordRef<R> {
+        self.catalog.get(id)
+    }
+
+    pub fn get_checked(&self, id: RecordId<R>) -> Option<RecordRef<R>> {
+        self.catalog.get_checked(id)
+    }
+
+    pub fn get_or_default(&self, id: RecordId<R>) -> R {
+        self.catalog.get_or_default(id)
+    }
+
+    pub fn peek(&self, id: RecordId<R>) -> R {
+        self.catalog.peek(id)
+    }
+
+    pub fn contains(&self, id: RecordId<R>) -> bool {
+        self.catalog.contains(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RecordId<R>, RecordRef<R>)> {
+        self.catalog.iter()
+    }
+
+    pub fn snapshot(&self) -> Snapshot<R> {
+        self.catalog.snapshot()
+    }
+
+    pub fn find<F>(&self, f: F) -> Vec<RecordId<R>>
+    where
+        F: Fn(&R) -> bool,
+    {
+        self.catalog.find(f)
+    }
+
+    pub fn find_first<F>(&self, f: F) -> Option<RecordId<R>>
+    where
+        F: Fn(&R) -> bool,
+    {
+        self.catalog.find_first(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.catalog.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.catalog.is_empty()
+    }
+
+    pub fn is_prototype(&self, id: RecordId<R>) -> bool {
+        self.catalog.is_prototype(id)
+    }
+
+    pub fn is_instance(&self, id: RecordId<R>) -> bool {
+        self.catalog.is_instance(id)
+    }
+
+    pub fn prototype_instances(&self, id: RecordId<R>) -> Vec<RecordId<R>> {
+        self.catalog.prototype_instances(id)
+    }
+
+    pub fn prototype_of(&self, id: RecordId<R>) -> Option<RecordId<R>> {
+        self.catalog.prototype_of(id)
+    }
+
+    pub fn diff_against_prototype(&self, id: RecordId<R>) -> Vec<&'static str> {
+        self.catalog.diff_against_prototype(id)
+    }
+
+    pub fn overridden_fields(&self, id: RecordId<R>) -> Vec<&'static str> {
+        self.catalog.overridden_fields(id)
+    }
+
+    pub fn changes(&self, start_point: Watermark, end_point: Watermark) -> CatalogIterator<'_, R> {
+        self.catalog.changes(start_point, end_point)
+    }
+
+    pub fn changes_for(
+        &self,
+        id: RecordId<R>,
+        start_point: Watermark,
+        end_point: Watermark,
+    ) -> impl Iterator<Item = Change<'_, R>> {
+        self.catalog.changes_for(id, start_point, end_point)
+    }
+
+    pub fn net_changes(&self, start_point: Watermark, end_point: Watermark) -> Vec<NetChange<R>> {
+        self.catalog.net_changes(start_point, end_point)
+    }
+
+    pub fn watermark(&self) -> Watermark {
+        self.catalog.watermark()
+    }
+}
+
+pub struct CatalogWriteGuard<'a, R>
+where
+    R: Record,
+{
+    catalog: &'a Catalog<R>,
+}
+
+impl<'a, R> Deref for CatalogWriteGuard<'a, R>
+where
+    R: Record,
+{
+    type Target = Catalog<R>;
+
+    fn deref(&self) -> &Catalog<R> {
+        self.catalog
+    }
+}
+
+impl<'a, R> Drop for CatalogWriteGuard<'a, R>
+where
+    R: Record,
+{
+    fn drop(&mut self) {
+        let mut state = self.catalog.state.inner.lock().unwrap();
+        state.exclusive = None;
+        self.catalog.state.locks_cv.notify_all();
+    }
+}
+
+pub struct RecordsGuard<'a, R>
+where
+    R: Record,
+{
+    catalog: &'a Catalog<R>,
+    inner: Option<MutexGuard<'a, CatalogStateInner<R>>>,
+    commits: Commits<R>,
+    // Rejection listeners may read the catalog, so they're only told once the state is released.
+    rejections: Vec<(RecordId<R>, ValidationError)>,
+}
+
+impl<'a, R> RecordsGuard<'a, R>
+where
+    R: Record,
+{
+    pub fn get(&self, id: RecordId<R>) -> Option<&R> {
+        Some(&self.state().live_record(id)?.inner)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RecordId<R>, &R)> {
+        iter_records(&self.state().records)
+    }
+
+    // Records can't be waited on while the state is held, so the commit is refused when the
+    // record or anything it cascades into is locked by another thread.
+    pub fn commit(&mut self, id: RecordId<R>, new_record: R) -> bool {
+        self.catalog.debug_assert_registered();
+        if !self.state().is_cascade_free(id) {
+            return false;
+        }
+        let state_inner = self.inner.as_mut().unwrap();
+        if let Err(error) = self.catalog.check_cascade(state_inner, id, &new_record) {
+            self.rejections.push((id, error));
+            return false;
+        }
+        self.catalog
+            .commit_locked(state_inner, id, None, new_record, &mut self.commits);
+        true
+    }
+
+    fn state(&self) -> &CatalogStateInner<R> {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl<'a, R> Drop for RecordsGuard<'a, R>
+where
+    R: Record,
+{
+    fn drop(&mut self) {
+        self.inner = None;
+        for (id, error) in &self.rejections {
+            self.catalog
+                .state
+                .commit_rejected_listeners
+                .notify(*id, error);
+        }
+        self.catalog.notify_commits(&self.commits);
+    }
+}
+
+impl<R> Catalog<R>
+where
+    R: Record,
+{
+    pub fn create(&self, record: R) -> RecordId<R> {
+        self.create_internal(RecordWrapper {
+            prototype_id: None,
+            prototype_instances: Default::default(),
+            override_mask: 0,
+            generation: 0,
+            inner: record,
+        })
+    }
+
+    pub fn create_from_prototype(&self, prototype_id: RecordId<R>) -> RecordId<R> {
+        self.create_from_prototype_with(prototype_id, |_| {})
+    }
+
+    // Fields `f` sets to something other than the prototype's value are overrides from the
+    // start, so later prototype edits leave them alone.
+    pub fn create_from_prototype_with<F>(&self, prototype_id: RecordId<R>, f: F) -> RecordId<R>
+    where
+        F: FnOnce(&mut R),
+    {
+        self.create_instance(prototype_id, |prototype| {
+            let mut instance = prototype.clone();
+            f(&mut instance);
+            let override_mask = field_mask::<R>(&instance.changed_fields(prototype));
+            (instance, override_mask)
+        })
+    }
+
+    // `f` gets the locked prototype's value and returns the instance with its override mask.
+    fn create_instance<F>(&self, prototype_id: RecordId<R>, f: F) -> RecordId<R>
+    where
+        F: FnOnce(&R) -> (R, u64),
+    {
+        let prototype_wrapper = self.get_internal(prototype_id, true);
+        let (instance, override_mask) = f(&prototype_wrapper.inner);
+        let instance_id = self.create_internal(RecordWrapper {
+            prototype_id: Some(prototype_id),
+            prototype_instances: Default::default(),
+            override_mask,
+            generation: 0,
+            inner: instance,
+        });
+
+        self.state
+            .inner
+            .lock()
+            .unwrap()
+            .own_record(prototype_id)
+            .unwrap()
+            .prototype_instances
+            .lock()
+            .unwrap()
+            .insert(instance_id);
+
+        self.unlock(prototype_id);
+        instance_id
+    }
+
+    // Like calling `create_from_prototype` `count` times, but the prototype is locked once and
+    // every instance is created under a single state lock.
+    pub fn bulk_create_from_prototype(
+        &self,
+        prototype_id: RecordId<R>,
+        count: usize,
+    ) -> Vec<RecordId<R>> {
+        self.debug_assert_registered();
+        let prototype_wrapper = self.get_internal(prototype_id, true);
+        let mut state = self.state.inner.lock().unwrap();
+        let mut changes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let index = state.records.len();
+            let instance_id = RecordId::with_generation(index, state.generation(index));
+            let instance_wrapper = Arc::from(RecordWrapper {
+                prototype_id: Some(prototype_id),
+                prototype_instances: Default::default(),
+                override_mask: 0,
+                generation: instance_id.1,
+                inner: prototype_wrapper.inner.clone(),
+            });
+            state.insert(instance_id, instance_wrapper.clone());
+            let lsn =
+                self.append_change_log(&mut state, instance_id, None, Some(&instance_wrapper));
+            changes.push((instance_id, lsn));
+        }
+        state
+            .own_record(prototype_id)
+            .unwrap()
+            .prototype_instances
+            .lock()
+            .unwrap()
+            .extend(changes.iter().map(|(instance_id, _)| *instance_id));
+        drop(state);
+        self.unlock(prototype_id);
+        self.notify_changes(&changes);
+        changes
+            .into_iter()
+            .map(|(instance_id, _)| instance_id)
+            .collect()
+    }
+
+    // Makes an instance a root prototype of its own, see `detach_prototype`. Returns `id`,
+    // which doesn't change.
+    pub fn promote_to_prototype(&self, id: RecordId<R>) -> RecordId<R> {
+        self.detach_prototype(id);
+        id
+    }
+
+    // Unlinks an instance from its prototype, keeping its current values but dropping its
+    // overrides, so later prototype edits leave it alone. Its own instances stay with it.
+    // Records that aren't instances are left as they are.
+    pub fn detach_prototype(&self, id: RecordId<R>) {
+        self.debug_assert_registered();
+        loop {
+            let prototype_id = match self.get_internal(id, false).prototype_id {
+                Some(prototype_id) => prototype_id,
+                None => return,
+            };
+
+            // Prototype before instance, like every other path that locks both. The record may
+            // have been reparented or detached before its lock was taken, then it's tried again.
+            let prototype_locked = self.try_get_internal(prototype_id, true).is_some();
+            let record_wrapper = self.get_internal(id, true);
+            if record_wrapper.prototype_id != Some(prototype_id) {
+                self.unlock(id);
+                if prototype_locked {
+                    self.unlock(prototype_id);
+                }
+                continue;
+            }
+
+            let mut state = self.state.inner.lock().unwrap();
+            if let Some(prototype_wrapper) = state.own_record(prototype_id) {
+                prototype_wrapper
+                    .prototype_instances
+                    .lock()
+                    .unwrap()
+                    .remove(&id);
+            }
+            let instance_ids = record_wrapper.prototype_instances.lock().unwrap().clone();
+            let detached = state.new_wrapper(
+                None,
+                instance_ids,
+                0,
+                record_wrapper.generation,
+                record_wrapper.inner.clone(),
+            );
+            state.set_record(id, Some(detached.clone()));
+            state.locks[id.0] = None;
+            if prototype_locked {
+                state.locks[prototype_id.0] = None;
+            }
+            self.state.locks_cv.notify_all();
+            self.write_change_log(id, None, Some(&detached), state);
+            return;
+        }
+    }
+
+    // Rebases `id` onto `new_prototype_id` without changing its id. Fields it inherited from
+    // its old prototype take the new prototype's values through `Record::proto_update`, and
+    // its overrides survive. A record that had no prototype keeps its values, and the ones
+    // that differ from the new prototype become overrides, like `create_from_prototype_with`.
+    // The change cascades into the record's own instances and is validated like a commit.
+    pub fn reparent_prototype(
+        &self,
+        id: RecordId<R>,
+        new_prototype_id: RecordId<R>,
+    ) -> Result<(), ReparentError> {
+        self.debug_assert_registered();
+        loop {
+            let old_prototype_id = self.get_internal(id, false).prototype_id;
+            if old_prototype_id == Some(new_prototype_id) {
+                return Ok(());
+            }
+            if self.try_get_internal(new_prototype_id, false).is_none() {
+                return Err(ReparentError::Missing(new_prototype_id.untyped()));
+            }
+            // Checked up front too, since locking a descendant along with its cascade would
+            // deadlock.
+            if descends_from(new_prototype_id, id, |id| self.prototype_of(id)) {
+                return Err(ReparentError::Cycle);
+            }
+
+            // Prototypes before instance, like every other path that locks both, and in id
+            // order between themselves. The record may have been reparented or detached before
+            // its lock was taken, then it's tried again.
+            let mut prototype_ids = vec![new_prototype_id];
+            prototype_ids.extend(old_prototype_id);
+            prototype_ids.sort_unstable_by_key(|prototype_id| prototype_id.0);
+            let locked_prototype_ids = prototype_ids
+                .into_iter()
+                .filter(|prototype_id| self.try_get_internal(*prototype_id, true).is_some())
+                .collect::<Vec<_>>();
+            let record_wrapper = match self.try_get_internal(id, true) {
+                Some(record_wrapper) if record_wrapper.prototype_id == old_prototype_id => {
+                    record_wrapper
+                }
+                record_wrapper => {
+                    if record_wrapper.is_some() {
+                        self.unlock(id);
+                    }
+                    for prototype_id in locked_prototype_ids {
+                        self.unlock(prototype_id);
+                    }
+                    continue;
+                }
+            };
+            let cascade_ids = self.lock_cascade(id, &[]);
+
+            let mut state = self.state.inner.lock().unwrap();
+            let mut commits = Commits::default();
+            let result = self.reparent_locked(
+                &mut state,
+                id,
+                &record_wrapper,
+                new_prototype_id,
+                &mut commits,
+            );
+            for locked_id in cascade_ids
+                .iter()
+                .chain(&locked_prototype_ids)
+                .chain(Some(&id))
+            {
+                state.locks[locked_id.0] = None;
+            }
+            self.state.locks_cv.notify_all();
+            drop(state);
+            if let Err(ReparentError::Invalid(error)) = &result {
+                self.state.commit_rejected_listeners.notify(id, error);
+            }
+            self.notify_commits(&commits);
+            return result;
+        }
+    }
+
+    // The rest of `reparent_prototype`, once the record, its prototypes and its cascade are
+    // locked. The caller releases them whatever this returns.
+    fn reparent_locked(
+        &self,
+        state: &mut MutexGuard<CatalogStateInner<R>>,
+        id: RecordId<R>,
+        record_wrapper: &RecordWrapper<R>,
+        new_prototype_id: RecordId<R>,
+        commits: &mut Commits<R>,
+    ) -> Result<(), ReparentError> {
+        if descends_from(new_prototype_id, id, |id| {
+            state.live_record(id).and_then(|w| w.prototype_id)
+        }) {
+            return Err(ReparentError::Cycle);
+        }
+        let new_prototype = state
+            .live_record(new_prototype_id)
+            .cloned()
+            .ok_or(ReparentError::Missing(new_prototype_id.untyped()))?;
+        let (new_record, override_mask) = match record_wrapper
+            .prototype_id
+            .and_then(|prototype_id| state.live_record(prototype_id))
+        {
+            Some(old_prototype) => (
+                record_wrapper.inner.proto_update_in_library(
+                    &old_prototype.inner,
+                    &new_prototype.inner,
+                    &self.library,
+                ),
+                record_wrapper.override_mask,
+            ),
+            None => (
+                record_wrapper.inner.clone(),
+                field_mask::<R>(&record_wrapper.inner.changed_fields(&new_prototype.inner)),
+            ),
+        };
+        self.check_cascade(state, id, &new_record)
+            .map_err(ReparentError::Invalid)?;
+
+        if let Some(old_prototype) = record_wrapper
+            .prototype_id
+            .and_then(|prototype_id| state.own_record(prototype_id))
+        {
+            old_prototype
+                .prototype_instances
+                .lock()
+                .unwrap()
+                .remove(&id);
+        }
+        if let Some(new_prototype) = state.own_record(new_prototype_id) {
+            new_prototype.prototype_instances.lock().unwrap().insert(id);
+        }
+        let instance_ids = record_wrapper.prototype_instances.lock().unwrap().clone();
+        let relinked = state.new_wrapper(
+            Some(new_prototype_id),
+            instance_ids,
+            override_mask,
+            record_wrapper.generation,
+            record_wrapper.inner.clone(),
+        );
+        state.set_record(id, Some(relinked));
+        // As a cascade from the new prototype, so the override mask is kept as set above.
+        self.commit_locked(state, id, Some(new_prototype_id), new_record, commits);
+        Ok(())
+    }
+
+    // Duplicates a single record. A copy of an instance is another instance of the same
+    // prototype, keeping the source's overrides, and the source's own instances stay with it.
+    pub fn clone_record(&self, id: RecordId<R>) -> RecordId<R> {
+        let record_wrapper = self.get_internal(id, false);
+        let record = record_wrapper.inner.clone();
+        match record_wrapper.prototype_id {
+            Some(prototype_id) => {
+                self.create_instance(prototype_id, |_| (record, record_wrapper.override_mask))
+            }
+            None => self.create(record),
+        }
+    }
+
+    pub fn create_with_id(&self, id: RecordId<R>, record: R) -> Result<(), IdError> {
+        self.create_with_id_internal(id, record, None)
+    }
+
+    // Like `create_with_id`, for replaying a log from elsewhere: the change gets `lsn` instead
+    // of a fresh one, and the sequencer moves past it.
+    pub fn create_with_lsn(&self, id: RecordId<R>, record: R, lsn: u64) -> Result<(), IdError> {
+        self.create_with_id_internal(id, record, Some(lsn))
+    }
+
+    fn create_with_id_internal(
+        &self,
+        id: RecordId<R>,
+        record: R,
+        lsn: Option<u64>,
+    ) -> Result<(), IdError> {
+        if !id.is_valid() {
+            return Err(IdError::Invalid);
+        }
+
+        self.debug_assert_registered();
+        let mut state = self.state.inner.lock().unwrap();
+        if let Some(Some(_)) = state.records.get(id.0) {
+            return Err(IdError::Occupied(id.untyped()));
+        }
+
+        let record_wrapper = Arc::from(RecordWrapper {
+            prototype_id: None,
+            prototype_instances: Default::default(),
+            override_mask: 0,
+            generation: id.1,
+            inner: record,
+        });
+        state.insert(id, record_wrapper.clone());
+        match lsn {
+            Some(lsn) => {
+                self.sequencer.advance_past(lsn);
+                self.append_change_log_at(&mut state, id, None, Some(&record_wrapper), lsn);
+                drop(state);
+                self.notify_changes(&[(id, lsn)]);
+            }
+            None => self.write_change_log(id, None, Some(&record_wrapper), state),
+        }
+        Ok(())
+    }
+
+    // Creates every record under a single state lock, so they get consecutive ids and their
+    // changes sit next to each other in the log.
+    pub fn create_many(&self, records: Vec<R>) -> Vec<RecordId<R>> {
+        self.debug_assert_registered();
+        let mut state = self.state.inner.lock().unwrap();
+        let mut changes = Vec::with_capacity(records.len());
+        for record in records {
+            let index = state.records.len();
+            let record_id = RecordId::with_generation(index, state.generation(index));
+            let record_wrapper = Arc::from(RecordWrapper {
+                prototype_id: None,
+                prototype_instances: Default::default(),
+                override_mask: 0,
+                generation: record_id.1,
+                inner: record,
+            });
+            state.insert(record_id, record_wrapper.clone());
+            let lsn = self.append_change_log(&mut state, record_id, None, Some(&record_wrapper));
+            changes.push((record_id, lsn));
+        }
+        drop(state);
+        self.notify_changes(&changes);
+        changes
+            .into_iter()
+            .map(|(record_id, _)| record_id)
+            .collect()
+    }
+
+    // Waits for every record to be unlocked, then makes `records` the catalog's records:
+    // the first one goes in slot 0 and so on, updating the record already there or creating
+    // one, and records past the end are deleted. Prototype links are all dropped. The changes
+    // share a `Change::transaction`, and readers see either all of them or none. Gives up with
+    // `Err(Shutdown)` once the catalog is shut down, like `lock`.
+    pub fn replace_all(&self, records: Vec<R>) -> Result<Vec<RecordId<R>>, Shutdown> {
+        self.debug_assert_registered();
+        let state = self.state.inner.lock().unwrap();
+        let mut state = self
+            .state
+            .locks_cv
+            .wait_while(state, |state| {
+                !state.shutdown
+                    && (state.exclusive.is_some() || state.locks.iter().any(Option::is_some))
+            })
+            .unwrap();
+        if state.shutdown {
+            return Err(Shutdown);
+        }
+        // Writes go to a copy of the published records until they're all done.
+        let staged = Arc::from(PublishedRecords::from_records(&state.records));
+        state.published = staged.clone();
+        state.transaction = Some(self.sequencer.next());
+
+        let slot_count = state.records.len().max(records.len());
+        let mut records = records.into_iter();
+        let mut ids = vec![];
+        let mut changes = vec![];
+        for index in 0..slot_count {
+            let old_id = state
+                .records
+                .get(index)
+                .and_then(Option::as_ref)
+                .map(|old_record| old_record.id_at(index));
+            match records.next() {
+                Some(record) => {
+                    let id = old_id.unwrap_or_else(|| {
+                        RecordId::with_generation(index, state.generation(index))
+                    });
+                    let record_wrapper =
+                        state.new_wrapper(None, Default::default(), 0, id.1, record);
+                    state.insert(id, record_wrapper.clone());
+                    let lsn = self.append_change_log(&mut state, id, None, Some(&record_wrapper));
+                    ids.push(id);
+                    changes.push((id, lsn));
+                }
+                None => {
+                    if let Some(old_id) = old_id {
+                        state.set_record(old_id, None);
+                        state.tombstones[index] = true;
+                        let lsn = self.append_change_log(&mut state, old_id, None, None);
+                        changes.push((old_id, lsn));
+                    }
+                }
+            }
+        }
+
+        state.transaction = None;
+        self.state.published.store_all(&staged);
+        state.published = self.state.published.clone();
+        drop(state);
+        self.notify_changes(&changes);
+        Ok(ids)
+    }
+
+    // Overwrites the wrapper's generation with the one of the slot it ends up in.
+    fn create_internal(&self, mut record_wrapper: RecordWrapper<R>) -> RecordId<R> {
+        self.debug_assert_registered();
+        let mut state = self.state.inner.lock().unwrap();
+        // Deleted slots aren't reused, so an id held elsewhere never ends up pointing at a
+        // different record. `create_with_id` can refill one explicitly.
+        let index = state.records.len();
+        let record_id = RecordId::with_generation(index, state.generation(index));
+        record_wrapper.generation = record_id.1;
+        let record_wrapper = Arc::from(record_wrapper);
+        state.insert(record_id, record_wrapper.clone());
+        self.write_change_log(record_id, None, Some(&record_wrapper), state);
+        record_id
+    }
+
+    pub fn get(&self, id: RecordId<R>) -> RecordRef<R> {
+        RecordRef {
+            record_wrapper: self.get_internal(id, false),
+        }
+    }
+
+    pub fn contains(&self, id: RecordId<R>) -> bool {
+        self.state.published.load(id).is_some()
+    }
+
+    pub fn peek(&self, id: RecordId<R>) -> R {
+        self.get_internal(id, false).inner.clone()
+    }
+
+    // Like `get`, but ids that were never created or have been deleted give `None` instead of
+    // panicking, for ids that come from user input or files.
+    pub fn get_checked(&self, id: RecordId<R>) -> Option<RecordRef<R>> {
+        Some(RecordRef {
+            record_wrapper: self.try_get_internal(id, false)?,
+        })
+    }
+
+    // Like `peek`, but the copy is shared, so it can be handed around and held across commits
+    // without cloning the record again.
+    pub fn get_arc(&self, id: RecordId<R>) -> Arc<R> {
+        Arc::from(self.peek(id))
+    }
+
+    // For callers that would rather show a default than handle a stale id.
+    pub fn get_or_default(&self, id: RecordId<R>) -> R {
+        self.state
+            .published
+            .load(id)
+            .map(|record_wrapper| record_wrapper.inner.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn try_get(&self, id: RecordId<R>) -> Option<R> {
+        let state = self.state.inner.lock().unwrap();
+        let record_wrapper = state.live_record(id)?;
+        if state.locks[id.0].is_some() {
+            return None;
+        }
+        Some(record_wrapper.inner.clone())
+    }
+
+    // Walks the records as they were when it was called, like `get` without pinning them.
+    // Later commits and deletes don't show up.
+    pub fn iter(&self) -> impl Iterator<Item = (RecordId<R>, RecordRef<R>)> {
+        let records = self.state.inner.lock().unwrap().records.clone();
+        records
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, record_wrapper)| {
+                let record_wrapper = record_wrapper?;
+                Some((record_wrapper.id_at(index), RecordRef { record_wrapper }))
+            })
+    }
+
+    // Reads every record in `ids` under a single lock of the state, in the same order. Panics
+    // on an id that was never created or has been deleted, like `get`.
+    pub fn get_many(&self, ids: &[RecordId<R>]) -> Vec<RecordRef<R>> {
+        let state = self.state.inner.lock().unwrap();
+        let mut records = Vec::with_capacity(ids.len());
+        for id in ids {
+            match state.live_record(*id) {
+                Some(record_wrapper) => records.push(RecordRef {
+                    record_wrapper: record_wrapper.clone(),
+                }),
+                // Released first, so the panic doesn't poison the state for everyone else.
+                None => {
+                    let message = state.missing_record_message(*id);
+                    drop(state);
+                    panic!("{}", message)
+                }
+            }
+        }
+        records
+    }
+
+    pub fn snapshot(&self) -> Snapshot<R> {
+        Snapshot {
+            records: self.state.inner.lock().unwrap().records.clone(),
+        }
+    }
+
+    pub fn get_versioned(&self, id: RecordId<R>) -> Option<(R, u64)> {
+        let state = self.state.inner.lock().unwrap();
+        let record_wrapper = state.live_record(id)?;
+        Some((record_wrapper.inner.clone(), state.versions[id.0]))
+    }
+
+    // Like `get_versioned` for every id in `ids`, read under a single lock of the state so the
+    // values are consistent with each other.
+    pub fn get_many_versioned(&self, ids: &[RecordId<R>]) -> Vec<Option<(R, u64)>> {
+        let state = self.state.inner.lock().unwrap();
+        ids.iter()
+            .map(|id| {
+                let record_wrapper = state.live_record(*id)?;
+                Some((record_wrapper.inner.clone(), state.versions[id.0]))
+            })
+            .collect()
+    }
+
+    // Fields where the instance differs from its prototype's current value. Empty for records
+    // that aren't instances, and for ones that don't exist.
+    pub fn diff_against_prototype(&self, id: RecordId<R>) -> Vec<&'static str> {
+        let state = self.state.inner.lock().unwrap();
+        let record_wrapper = match state.live_record(id) {
+            Some(record_wrapper) => record_wrapper,
+            None => return vec![],
+        };
+        match record_wrapper
+            .prototype_id
+            .and_then(|prototype_id| state.live_record(prototype_id))
+        {
+            Some(prototype_wrapper) => record_wrapper
+                .inner
+                .changed_fields(&prototype_wrapper.inner),
+            None => vec![],
+        }
+    }
+
+    // Fields set on the instance itself rather than inherited from its prototype, going by
+    // `Record::field_names`. Unlike `diff_against_prototype` this is remembered, so a field
+    // set to the prototype's value still counts, and it survives saving and loading.
+    pub fn overridden_fields(&self, id: RecordId<R>) -> Vec<&'static str> {
+        self.get_internal(id, false).overridden_fields()
+    }
+
+    pub fn is_prototype(&self, id: RecordId<R>) -> bool {
+        self.state.published.load(id).is_some_and(|record_wrapper| {
+            !record_wrapper
+                .prototype_instances
+                .lock()
+                .unwrap()
+                .is_empty()
+        })
+    }
+
+    pub fn is_instance(&self, id: RecordId<R>) -> bool {
+        self.state
+            .published
+            .load(id)
+            .is_some_and(|record_wrapper| record_wrapper.prototype_id.is_some())
+    }
+
+    // The live instances directly derived from `id`, in id order. Like `is_instance`, this
+    // reads without taking any record locks.
+    pub fn prototype_instances(&self, id: RecordId<R>) -> Vec<RecordId<R>> {
+        let instance_ids = match self.state.published.load(id) {
+            Some(record_wrapper) => record_wrapper.prototype_instances.lock().unwrap().clone(),
+            None => return vec![],
+        };
+        let mut instance_ids = instance_ids
+            .into_iter()
+            .filter(|instance_id| self.prototype_of(*instance_id) == Some(id))
+            .collect::<Vec<_>>();
+        instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+        instance_ids
+    }
+
+    pub fn prototype_of(&self, id: RecordId<R>) -> Option<RecordId<R>> {
+        self.state.published.load(id)?.prototype_id
+    }
+
+    // Waits for `id` to be free. Threads still waiting when `shutdown` is called give up with
+    // `Err(Shutdown)`, as does every call after it.
+    pub fn lock(&self, id: RecordId<R>) -> Result<Locked<'_, R>, Shutdown> {
+        let state = self.state.inner.lock().unwrap();
+        let mut state = self
+            .state
+            .locks_cv
+            .wait_while(state, |library| {
+                !library.shutdown
+                    && (matches!(library.locks.get(id.0), Some(Some(_)))
+                        || library.is_exclusive_to_other_thread())
+            })
+            .unwrap();
+        if state.shutdown {
+            return Err(Shutdown);
+        }
+        let record_wrapper = match state.live_record(id).cloned() {
+            Some(record_wrapper) => record_wrapper,
+            None => {
+                let message = state.missing_record_message(id);
+                drop(state);
+                panic!("{}", message)
+            }
+        };
+        state.locks[id.0] = Some(thread::current().id());
+        drop(state);
+        Ok(Locked {
+            id,
+            value: self.unwrap_record_wrapper(&record_wrapper),
+            catalog: self,
+        })
+    }
+
+    // Wakes every thread blocked in `lock` with `Err(Shutdown)` and makes later calls to `lock`,
+    // `try_lock` and `lock_timeout` fail, so worker threads can exit. Locks already held stay
+    // valid and can still be committed and released.
+    pub fn shutdown(&self) {
+        self.state.inner.lock().unwrap().shutdown = true;
+        self.state.locks_cv.notify_all();
+    }
+
+    fn get_internal(&self, id: RecordId<R>, lock: bool) -> Arc<RecordWrapper<R>> {
+        self.try_get_internal(id, lock).unwrap_or_else(|| {
+            let message = self.state.inner.lock().unwrap().missing_record_message(id);
+            panic!("{}", message)
+        })
+    }
+
+    fn try_get_internal(&self, id: RecordId<R>, lock: bool) -> Option<Arc<RecordWrapper<R>>> {
+        if !lock {
+            return self.state.published.load(id);
+        }
+
+        let state = self.state.inner.lock().unwrap();
+        state.live_record(id)?;
+        let mut state = self
+            .state
+            .locks_cv
+            .wait_while(state, |library| {
+                matches!(library.locks.get(id.0), Some(Some(_)))
+                    || library.is_exclusive_to_other_thread()
+            })
+            .unwrap();
+        let record = state.live_record(id)?.clone();
+        state.locks[id.0] = Some(thread::current().id());
+        Some(record)
+    }
+
+    fn unwrap_record_wrapper(&self, record_wrapper: &Arc<RecordWrapper<R>>) -> &R {
+        self.reads.lock().unwrap().push(record_wrapper.clone());
+        unsafe {
+            let record_ref = Arc::as_ptr(record_wrapper).as_ref().unwrap();
+            &<&RecordWrapper<R>>::clone(&record_ref).inner
+        }
+    }
+
+    // Every `Locked` value points into a record kept alive in `reads`, which otherwise only grows
+    // for as long as this checkout lives. Long-lived checkouts that lock a lot should call this
+    // now and then. Taking `&mut self` makes sure no `Locked` from this checkout is still around.
+    pub fn clear_reads(&mut self) {
+        self.reads.get_mut().unwrap().clear();
+    }
+
+    // Like `lock`, but gives `None` right away instead of waiting when the record is already
+    // locked, by any thread, or doesn't exist.
+    pub fn try_lock(&self, id: RecordId<R>) -> Option<Locked<'_, R>> {
+        let mut state = self.state.inner.lock().unwrap();
+        if state.shutdown
+            || matches!(state.locks.get(id.0), Some(Some(_)))
+            || state.is_exclusive_to_other_thread()
+        {
+            return None;
+        }
+        let record_wrapper = state.live_record(id)?.clone();
+        state.locks[id.0] = Some(thread::current().id());
+        drop(state);
+        Some(Locked {
+            id,
+            value: self.unwrap_record_wrapper(&record_wrapper),
+            catalog: self,
+        })
+    }
+
+    // Like `lock`, but gives up with `None` if the record isn't free within `timeout`, or
+    // doesn't exist.
+    pub fn lock_timeout(&self, id: RecordId<R>, timeout: Duration) -> Option<Locked<'_, R>> {
+        let state = self.state.inner.lock().unwrap();
+        state.live_record(id)?;
+        let (mut state, wait_result) = self
+            .state
+            .locks_cv
+            .wait_timeout_while(state, timeout, |library| {
+                !library.shutdown
+                    && (matches!(library.locks.get(id.0), Some(Some(_)))
+                        || library.is_exclusive_to_other_thread())
+            })
+            .unwrap();
+        if wait_result.timed_out() || state.shutdown {
+            return None;
+        }
+        let record_wrapper = state.live_record(id)?.clone();
+        state.locks[id.0] = Some(thread::current().id());
+        drop(state);
+        Some(Locked {
+            id,
+            value: self.unwrap_record_wrapper(&record_wrapper),
+            catalog: self,
+        })
+    }
+
+    // Shorthand for locking `id` and committing a mutated clone of it. The lock is released
+    // even if `f` panics. Fails without calling `f` once the catalog has been shut down.
+    pub fn edit<F>(&self, id: RecordId<R>, f: F) -> Result<(), EditError>
+    where
+        F: FnOnce(&mut R),
+    {
+        Ok(self.lock(id)?.mutate(f)?)
+    }
+
+    pub fn lock_all(&self) -> CatalogWriteGuard<'_, R> {
+        let state = self.state.inner.lock().unwrap();
+        let mut state = self
+            .state
+            .locks_cv
+            .wait_while(state, |library| {
+                library.exclusive.is_some() || library.locks.iter().any(Option::is_some)
+            })
+            .unwrap();
+        state.exclusive = Some(thread::current().id());
+        CatalogWriteGuard { catalog: self }
+    }
+
+    // Does nothing for ids that aren't locked, including unknown and stale ones.
+    pub fn unlock(&self, id: RecordId<R>) {
+        let mut state = self.state.inner.lock().unwrap();
+        if state.lock_owner(id).is_some() {
+            state.locks[id.0] = None;
+            self.state.locks_cv.notify_all();
+        }
+    }
+
+    pub fn is_locked(&self, id: RecordId<R>) -> bool {
+        self.state.inner.lock().unwrap().lock_owner(id).is_some()
+    }
+
+    pub fn is_locked_by_current_thread(&self, id: RecordId<R>) -> bool {
+        self.state.inner.lock().unwrap().lock_owner(id) == Some(thread::current().id())
+    }
+
+    // Rejected, leaving every record as it was, when the record or any instance value its
+    // prototype cascade would write fails validation.
+    pub fn commit(&self, locked: &Locked<R>, new_record: R) -> Result<(), ValidationError> {
+        self.commit_internal(locked.id, None, new_record)
+    }
+
+    // Like `commit`, but for a value the record inherited rather than one set on it, such as
+    // one a prototype edit cascaded into it. It's logged as caused by the record's prototype
+    // and its overrides are left as they are, so undoing a prototype edit by restoring each
+    // record doesn't turn the instances' inherited fields into overrides.
+    pub fn commit_inherited(
+        &self,
+        locked: &Locked<R>,
+        new_record: R,
+    ) -> Result<(), ValidationError> {
+        let cause = self.get_internal(locked.id, false).prototype_id;
+        self.commit_internal(locked.id, cause, new_record)
+    }
+
+    // For replaying a log from elsewhere: the change gets `lsn` instead of a fresh one, and the
+    // sequencer moves past it. Instances aren't updated, since the log being replayed has
+    // their changes too.
+    pub fn commit_with_lsn(
+        &self,
+        locked: &Locked<R>,
+        new_record: R,
+        lsn: u64,
+    ) -> Result<(), ValidationError> {
+        if let Err(error) = self.state.validator.check(&new_record) {
+            self.state
+                .commit_rejected_listeners
+                .notify(locked.id, &error);
+            return Err(error);
+        }
+        self.debug_assert_registered();
+        self.sequencer.advance_past(lsn);
+        let mut state_inner = self.state.inner.lock().unwrap();
+        let old_record = state_inner.record(locked.id).clone();
+        let instance_ids = old_record.prototype_instances.lock().unwrap().clone();
+        let new_record = state_inner.new_wrapper(
+            old_record.prototype_id,
+            instance_ids,
+            old_record.override_mask_after(&new_record),
+            old_record.generation,
+            new_record,
+        );
+        state_inner.set_record(locked.id, Some(new_record.clone()));
+        self.append_change_log_at(&mut state_inner, locked.id, None, Some(&new_record), lsn);
+        drop(state_inner);
+        self.notify_commits(&Commits {
+            changes: vec![(locked.id, lsn)],
+            records: vec![(locked.id, old_record, new_record)],
+        });
+        Ok(())
+    }
+
+    // Skips the commit, and its change log entry, when `Record::content_eq` says nothing
+    // changed. Returns whether a commit was written.
+    pub fn commit_if_changed(
+        &self,
+        locked: &Locked<R>,
+        new_record: R,
+    ) -> Result<bool, ValidationError>
+    where
+        R: PartialEq,
+    {
+        if new_record.content_eq(&self.get_internal(locked.id, false).inner) {
+            return Ok(false);
+        }
+        self.commit(locked, new_record)?;
+        Ok(true)
+    }
+
+    // Compare-and-swap for values computed from a read taken before `locked` was: commits
+    // `new_record` only if the record still equals `expected`. Otherwise, or if it fails
+    // validation, nothing is written and `new_record` is handed back.
+    pub fn commit_if_unchanged(
+        &self,
+        locked: &Locked<R>,
+        expected: &R,
+        new_record: R,
+    ) -> Result<(), R>
+    where
+        R: PartialEq,
+    {
+        if self.get_internal(locked.id, false).inner != *expected {
+            return Err(new_record);
+        }
+        let rejected = new_record.clone();
+        self.commit(locked, new_record).map_err(|_| rejected)
+    }
+
+    // Logs a change that keeps the record's current value, so change listeners re-evaluate it
+    // and its version moves on. Instances aren't touched along with it. Returns false, and logs
+    // nothing, if the record doesn't exist.
+    pub fn touch(&self, id: RecordId<R>) -> bool {
+        self.debug_assert_registered();
+        let state_inner = self.state.inner.lock().unwrap();
+        let record_wrapper = match state_inner.live_record(id) {
+            Some(record_wrapper) => record_wrapper.clone(),
+            None => return false,
+        };
+        self.write_change_log(id, None, Some(&record_wrapper), state_inner);
+        true
+    }
+
+    // Like `scoped_subscribe`, but only for changes whose new record matches `pred`. The
+    // predicate sees the record as that change wrote it, and deletes never match.
+    pub fn subscribe_filtered<P, F>(&self, pred: P, f: F) -> Subscription
+    where
+        P: Fn(&R) -> bool + Send + Sync + 'static,
+        F: Fn(RecordId<R>, u64) + Send + Sync + 'static,
+    {
+        self.change_listeners
+            .subscribe_sink(Arc::from(move |change: &AnyChange| {
+                if change.type_name() == R::type_name()
+                    && change.new_record::<R>().is_some_and(&pred)
+                {
+                    f(change.record_id().typed(), change.lsn());
+                }
+            }))
+    }
+
+    // Calls `f` for every change to this catalog's records until the subscription is dropped.
+    pub fn scoped_subscribe<F>(&self, f: F) -> Subscription
+    where
+        F: Fn(RecordId<R>, u64) + Send + Sync + 'static,
+    {
+        self.change_listeners.subscribe(Arc::from(
+            move |type_name: &'static str, id: RecordId, lsn| {
+                if type_name == R::type_name() {
+                    f(id.typed(), lsn);
+                }
+            },
+        ))
+    }
+
+    pub fn on_commit_rejected<F>(&self, f: F)
+    where
+        F: Fn(RecordId<R>, ValidationError) + Send + Sync + 'static,
+    {
+        self.state
+            .commit_rejected_listeners
+            .listeners
+            .lock()
+            .unwrap()
+            .push(Arc::from(f));
+    }
+
+    // Calls `f` with the old and new value of every record a commit writes, prototype cascades
+    // included, once the catalog's state is released so `f` can read the catalog.
+    pub fn subscribe<F>(&self, f: F)
+    where
+        F: Fn(RecordId<R>, Option<&R>, &R) + Send + Sync + 'static,
+    {
+        self.state
+            .commit_observers
+            .observers
+            .lock()
+            .unwrap()
+            .push(Arc::from(f));
+    }
+
+    pub fn lock_records(&self) -> RecordsGuard<'_, R> {
+        RecordsGuard {
+            catalog: self,
+            inner: Some(self.state.inner.lock().unwrap()),
+            commits: Default::default(),
+            rejections: Default::default(),
+        }
+    }
+
+    // The branch starts out with this catalog's validator and commit rejection listeners, but
+    // ones set afterwards only apply to the catalog they're set on.
+    pub fn branch(&self) -> Catalog<R> {
+        let state_inner = self.state.inner.lock().unwrap();
+        let record_count = state_inner.records.len();
+        let branch_inner = CatalogStateInner {
+            locks: vec![None; record_count],
+            records: state_inner.records.clone(),
+            history: state_inner.history.clone(),
+            versions: state_inner.versions.clone(),
+            inherited: vec![true; record_count],
+            tombstones: state_inner.tombstones.clone(),
+            generations: state_inner.generations.clone(),
+            ..Default::default()
+        };
+        drop(state_inner);
+        let mut branch_state = CatalogState::from_inner(self.generation, branch_inner);
+        branch_state.validator = CommitValidator {
+            validator: Mutex::from(self.state.validator.validator.lock().unwrap().clone()),
+        };
+        branch_state.commit_rejected_listeners = CommitRejectedListeners {
+            listeners: Mutex::from(
+                self.state
+                    .commit_rejected_listeners
+                    .listeners
+                    .lock()
+                    .unwrap()
+                    .clone(),
+            ),
+        };
+        Catalog {
+            state: Arc::from(branch_state),
+            reads: Default::default(),
+            sequencer: self.sequencer.clone(),
+            change_listeners: Default::default(),
+            #[cfg(feature = "serde")]
+            write_ahead_log: Default::default(),
+            generation: self.generation,
+            library: self.library.clone(),
+        }
+    }
+
+    pub fn cascade_histogram(&self) -> Vec<(usize, u64)> {
+        self.state
+            .inner
+            .lock()
+            .unwrap()
+            .cascade_histogram
+            .iter()
+            .map(|(cascade_size, count)| (*cascade_size, *count))
+            .collect()
+    }
+
+    // Every live record with its prototype and instances, one record per line, for logs and
+    // test failures.
+    pub fn debug_dump(&self) -> String {
+        let state_inner = self.state.inner.lock().unwrap();
+        let mut dump = String::new();
+        for (index, record_wrapper) in state_inner.records.iter().enumerate() {
+            let record_wrapper = match record_wrapper {
+                Some(record_wrapper) => record_wrapper,
+                None => continue,
+            };
+            let _ = write!(
+                dump,
+                "{:?}: {:?}",
+                record_wrapper.id_at(index),
+                record_wrapper.inner
+            );
+            if let Some(prototype_id) = record_wrapper.prototype_id {
+                let _ = write!(dump, " prototype: {:?}", prototype_id);
+            }
+            let mut instance_ids = record_wrapper
+                .prototype_instances
+                .lock()
+                .unwrap()
+                .iter()
+                .copied()
+                .collect::<Vec<_>>();
+            if !instance_ids.is_empty() {
+                instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+                let _ = write!(dump, " instances: {:?}", instance_ids);
+            }
+            dump.push('\n');
+        }
+        dump
+    }
+
+    // The caller holds the lock on `id`. Every instance the commit cascades into is locked
+    // up front (prototype before instance, like `create_from_prototype`), then the whole
+    // cascade is written under a single state lock.
+    fn commit_internal(
+        &self,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: R,
+    ) -> Result<(), ValidationError> {
+        self.debug_assert_registered();
+        let cascade_ids = self.lock_cascade(id, &[]);
+        let mut state_inner = self.state.inner.lock().unwrap();
+        let mut commits = Commits::default();
+        let result = self.check_cascade(&state_inner, id, &new_record);
+        if result.is_ok() {
+            self.commit_locked(&mut state_inner, id, cause, new_record, &mut commits);
+        }
+        for instance_id in &cascade_ids {
+            state_inner.locks[instance_id.0] = None;
+        }
+        self.state.locks_cv.notify_all();
+        drop(state_inner);
+        if let Err(error) = &result {
+            self.state.commit_rejected_listeners.notify(id, error);
+        }
+        self.notify_commits(&commits);
+        result
+    }
+
+    // Validates `new_record` and, without writing anything, every instance value committing it
+    // would cascade into. Needs the cascade locked so the instances can't change underneath.
+    fn check_cascade(
+        &self,
+        state_inner: &CatalogStateInner<R>,
+        id: RecordId<R>,
+        new_record: &R,
+    ) -> Result<(), ValidationError> {
+        self.state.validator.check(new_record)?;
+        let old_record = state_inner.record(id);
+        let instance_ids = old_record.prototype_instances.lock().unwrap().clone();
+        for instance_id in instance_ids {
+            if let Some(instance_wrapper) = state_inner.live_record(instance_id) {
+                if instance_wrapper.prototype_id == Some(id) {
+                    let new_instance = instance_wrapper.inner.proto_update_in_library(
+                        &old_record.inner,
+                        new_record,
+                        &self.library,
+                    );
+                    self.check_cascade(state_inner, instance_id, &new_instance)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every commit, and every instance update a prototype commit cascades into, is checked by
+    // `f` after `Record::validate`. A commit it rejects is refused and changes nothing.
+    pub fn set_validator<F>(&self, f: F)
+    where
+        F: Fn(&R) -> Result<(), String> + Send + Sync + 'static,
+    {
+        *self.state.validator.validator.lock().unwrap() = Some(Arc::from(f));
+    }
+
+    // Instances in `held` are already locked by the caller, so they're walked through without
+    // being locked again.
+    fn lock_cascade(&self, id: RecordId<R>, held: &[RecordId<R>]) -> Vec<RecordId<R>> {
+        let mut locked_ids = vec![];
+        let mut pending_ids = vec![id];
+        while let Some(prototype_id) = pending_ids.pop() {
+            let instance_ids = self
+                .get_internal(prototype_id, false)
+                .prototype_instances
+                .lock()
+                .unwrap()
+                .clone();
+            for instance_id in instance_ids {
+                let is_held = held.contains(&instance_id);
+                if let Some(instance_wrapper) = self.try_get_internal(instance_id, !is_held) {
+                    if !is_held {
+                        locked_ids.push(instance_id);
+                    }
+                    if instance_wrapper.prototype_id == Some(prototype_id) {
+                        pending_ids.push(instance_id);
+                    }
+                }
+            }
+        }
+        locked_ids
+    }
+
+    // Commits several records as one. They're locked in id order, `f` edits clones of them,
+    // and once everything they cascade into is locked too, all of it is written under a single
+    // state lock. If `f` panics or any record fails validation, nothing is committed. Records
+    // `f` removes from the map are left as they are. The changes share a
+    // `Change::transaction`, which undo treats as a single step.
+    pub fn transaction<F>(&self, ids: &[RecordId<R>], f: F) -> Result<(), EditError>
+    where
+        F: FnOnce(&mut HashMap<RecordId<R>, R>),
+    {
+        self.debug_assert_registered();
+        let mut ids = ids.to_vec();
+        ids.sort_unstable_by_key(|id| id.0);
+        ids.dedup();
+        let locks = ids
+            .iter()
+            .map(|id| self.lock(*id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut records = locks
+            .iter()
+            .map(|locked| (locked.id, locked.value.clone()))
+            .collect::<HashMap<_, _>>();
+        f(&mut records);
+
+        let mut held = ids.clone();
+        let mut cascade_ids = vec![];
+        for id in &ids {
+            let locked_ids = self.lock_cascade(*id, &held);
+            held.extend(&locked_ids);
+            cascade_ids.extend(locked_ids);
+        }
+        let mut state_inner = self.state.inner.lock().unwrap();
+        let rejected = ids.iter().find_map(|id| {
+            let record = records.get(id)?;
+            self.check_cascade(&state_inner, *id, record)
+                .err()
+                .map(|error| (*id, error))
+        });
+        if let Some((id, error)) = rejected {
+            for instance_id in &cascade_ids {
+                state_inner.locks[instance_id.0] = None;
+            }
+            self.state.locks_cv.notify_all();
+            drop(state_inner);
+            self.state.commit_rejected_listeners.notify(id, &error);
+            return Err(EditError::Invalid(error));
+        }
+        state_inner.transaction = Some(self.sequencer.next());
+        let mut commits = Commits::default();
+        for id in &ids {
+            if let Some(record) = records.remove(id) {
+                self.commit_locked(&mut state_inner, *id, None, record, &mut commits);
+            }
+        }
+        state_inner.transaction = None;
+        for instance_id in &cascade_ids {
+            state_inner.locks[instance_id.0] = None;
+        }
+        self.state.locks_cv.notify_all();
+        drop(state_inner);
+        self.notify_commits(&commits);
+        drop(locks);
+        Ok(())
+    }
+
+    pub(crate) fn commit_locked(
+        &self,
+        state_inner: &mut MutexGuard<CatalogStateInner<R>>,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: R,
+        commits: &mut Commits<R>,
+    ) {
+        let cascade_start = commits.changes.len();
+        let old_record = state_inner.record(id).clone();
+        let instance_ids = old_record.prototype_instances.lock().unwrap().clone();
+        let override_mask = match cause {
+            Some(_) => old_record.override_mask,
+            None => old_record.override_mask_after(&new_record),
+        };
+        let new_record = state_inner.new_wrapper(
+            old_record.prototype_id,
+            instance_ids.clone(),
+            override_mask,
+            old_record.generation,
+            new_record,
+        );
+        state_inner.set_record(id, Some(new_record.clone()));
+        let lsn = self.append_change_log(state_inner, id, cause, Some(&new_record));
+        commits.changes.push((id, lsn));
+        commits
+            .records
+            .push((id, old_record.clone(), new_record.clone()));
+
+        // Instances cascade in id order so the same edit always assigns the same LSNs.
+        let mut instance_ids = instance_ids.into_iter().collect::<Vec<_>>();
+        instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+        for instance_id in instance_ids {
+            if let Some(instance_wrapper) = state_inner.live_record(instance_id).cloned() {
+                if instance_wrapper.prototype_id == Some(id) {
+                    let new_instance = instance_wrapper.inner.proto_update_in_library(
+                        &old_record.inner,
+                        &new_record.inner,
+                        &self.library,
+                    );
+                    self.commit_locked(state_inner, instance_id, Some(id), new_instance, commits);
+                }
+            }
+        }
+
+        if cause.is_none() {
+            *state_inner
+                .cascade_histogram
+                .entry(commits.changes.len() - cascade_start)
+                .or_default() += 1;
+        }
+    }
+
+    pub fn delete(&self, id: RecordId<R>) {
+        let record_wrapper = self.get_internal(id, true);
+        self.delete_internal(id, record_wrapper);
+    }
+
+    // Deletes `id`, then moves the last record into its slot and shrinks the catalog, so
+    // deleting never leaves tombstones behind. Returns the id the moved record used to have,
+    // which no longer exists afterwards, and its new one. The slot's generation still moves on,
+    // so the new id isn't equal to `id`.
+    pub fn delete_swap(&self, id: RecordId<R>) -> Option<(RecordId<R>, RecordId<R>)> {
+        self.delete(id);
+        loop {
+            let last_id = {
+                let mut state_inner = self.state.inner.lock().unwrap();
+                let live_len = state_inner
+                    .records
+                    .iter()
+                    .rposition(Option::is_some)
+                    .map_or(0, |index| index + 1);
+                state_inner.truncate(live_len);
+                let last_index = live_len.checked_sub(1)?;
+                state_inner.records[last_index].as_ref()?.id_at(last_index)
+            };
+            if last_id.0 < id.0 {
+                return None;
+            }
+
+            // Prototype before instance, like every other path that locks both.
+            let prototype_id = match self.state.published.load(last_id) {
+                Some(record_wrapper) => record_wrapper.prototype_id,
+                None => continue,
+            };
+            if let Some(prototype_id) = prototype_id {
+                if self.try_get_internal(prototype_id, true).is_none() {
+                    continue;
+                }
+            }
+            if let Some(record_wrapper) = self.try_get_internal(last_id, true) {
+                let relocated_id = match record_wrapper.prototype_id == prototype_id {
+                    true => self.relocate(last_id, id, record_wrapper),
+                    false => None,
+                };
+                if let Some(relocated_id) = relocated_id {
+                    if let Some(prototype_id) = prototype_id {
+                        self.unlock(prototype_id);
+                    }
+                    return Some((last_id, relocated_id));
+                }
+                self.unlock(last_id);
+            }
+            if let Some(prototype_id) = prototype_id {
+                self.unlock(prototype_id);
+            }
+            if matches!(
+                self.state.inner.lock().unwrap().records.get(id.0),
+                Some(Some(_))
+            ) {
+                return None;
+            }
+        }
+    }
+
+    // The caller holds the locks on `from` and its prototype. Refuses when `from` stopped being
+    // the last record or `to`'s slot was refilled in the meantime. Returns the moved record's
+    // new id.
+    fn relocate(
+        &self,
+        from: RecordId<R>,
+        to: RecordId<R>,
+        record_wrapper: Arc<RecordWrapper<R>>,
+    ) -> Option<RecordId<R>> {
+        self.debug_assert_registered();
+        let mut state_inner = self.state.inner.lock().unwrap();
+        if state_inner.records.len() != from.0 + 1 || state_inner.records[to.0].is_some() {
+            return None;
+        }
+
+        let to = RecordId::with_generation(to.0, state_inner.generation(to.0));
+        let instance_ids = record_wrapper.prototype_instances.lock().unwrap().clone();
+        let relocated = Arc::from(RecordWrapper {
+            prototype_id: record_wrapper.prototype_id,
+            prototype_instances: Mutex::from(instance_ids.clone()),
+            override_mask: record_wrapper.override_mask,
+            generation: to.1,
+            inner: record_wrapper.inner.clone(),
+        });
+        state_inner.set_record(to, Some(relocated.clone()));
+        let created_lsn = self.append_change_log(&mut state_inner, to, None, Some(&relocated));
+        let mut changes = vec![(to, created_lsn)];
+        if let Some(prototype_id) = record_wrapper.prototype_id {
+            if let Some(prototype_wrapper) = state_inner.own_record(prototype_id) {
+                let mut prototype_instances = prototype_wrapper.prototype_instances.lock().unwrap();
+                prototype_instances.remove(&from);
+                prototype_instances.insert(to);
+            }
+        }
+        // Relinked instances are logged too, so a replayed log points them at the new id.
+        let mut instance_ids = instance_ids.into_iter().collect::<Vec<_>>();
+        instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+        for instance_id in instance_ids {
+            if let Some(instance_wrapper) = state_inner.live_record(instance_id).cloned() {
+                if instance_wrapper.prototype_id == Some(from) {
+                    let relinked = Arc::from(RecordWrapper {
+                        prototype_id: Some(to),
+                        prototype_instances: Mutex::from(
+                            instance_wrapper.prototype_instances.lock().unwrap().clone(),
+                        ),
+                        override_mask: instance_wrapper.override_mask,
+                        generation: instance_wrapper.generation,
+                        inner: instance_wrapper.inner.clone(),
+                    });
+                    state_inner.set_record(instance_id, Some(relinked.clone()));
+                    let lsn = self.append_change_log(
+                        &mut state_inner,
+                        instance_id,
+                        Some(to),
+                        Some(&relinked),
+                    );
+                    changes.push((instance_id, lsn));
+                }
+            }
+        }
+
+        state_inner.set_record(from, None);
+        let deleted_lsn = self.append_change_log(&mut state_inner, from, None, None);
+        changes.push((from, deleted_lsn));
+        state_inner.truncate(from.0);
+        self.state.locks_cv.notify_all();
+        drop(state_inner);
+        self.notify_changes(&changes);
+        Some(to)
+    }
+
+    // Ids of the live records `f` accepts, in id order. `f` runs with the state held, so it
+    // must not use this catalog.
+    pub fn find<F>(&self, f: F) -> Vec<RecordId<R>>
+    where
+        F: Fn(&R) -> bool,
+    {
+        let state = self.state.inner.lock().unwrap();
+        iter_records(&state.records)
+            .filter(|(_, record)| f(record))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    // Like `find`, but stops at the first match.
+    pub fn find_first<F>(&self, f: F) -> Option<RecordId<R>>
+    where
+        F: Fn(&R) -> bool,
+    {
+        let state = self.state.inner.lock().unwrap();
+        let first = iter_records(&state.records)
+            .find(|(_, record)| f(record))
+            .map(|(id, _)| id);
+        first
+    }
+
+    pub fn count_where<F>(&self, f: F) -> usize
+    where
+        F: Fn(&R) -> bool,
+    {
+        self.state
+            .inner
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .flatten()
+            .filter(|record_wrapper| f(&record_wrapper.inner))
+            .count()
+    }
+
+    // Deleted records aren't counted.
+    pub fn len(&self) -> usize {
+        self.state.record_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Records whose `Record::referenced_ids` point at records that don't exist, each with the
+    // ids that are missing. Meant as a check before saving.
+    pub fn find_dangling(&self) -> Vec<DanglingReferences<R>> {
+        self.iter()
+            .filter_map(|(id, record)| {
+                let dangling_ids = record
+                    .referenced_ids()
+                    .into_iter()
+                    .filter(|(type_name, referenced_id)| {
+                        !self.library.contains_record(type_name, *referenced_id)
+                    })
+                    .collect::<Vec<_>>();
+                if dangling_ids.is_empty() {
+                    None
+                } else {
+                    Some((id, dangling_ids))
+                }
+            })
+            .collect()
+    }
+
+    pub fn retain<F>(&self, f: F) -> usize
+    where
+        F: Fn(&R) -> bool,
+    {
+        let candidates = self
+            .state
+            .inner
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .enumerate()
+            .filter_map(|(index, record)| match record {
+                Some(record_wrapper) if !f(&record_wrapper.inner) => {
+                    Some(record_wrapper.id_at(index))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut removed = 0;
+        for id in candidates {
+            if let Some(record_wrapper) = self.try_get_internal(id, true) {
+                if f(&record_wrapper.inner) {
+                    self.unlock(id);
+                } else {
+                    self.delete_internal(id, record_wrapper);
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    // Deleting an instance drops it from its prototype, deleting a prototype detaches its
+    // instances so they become standalone records holding their current values.
+    fn delete_internal(&self, id: RecordId<R>, record_wrapper: Arc<RecordWrapper<R>>) {
+        self.debug_assert_registered();
+        if let Some(prototype_id) = record_wrapper.prototype_id {
+            if let Some(prototype_wrapper) =
+                self.state.inner.lock().unwrap().own_record(prototype_id)
+            {
+                prototype_wrapper
+                    .prototype_instances
+                    .lock()
+                    .unwrap()
+                    .remove(&id);
+            }
+        }
+
+        // Instances are locked first, then detached and logged in one go so the log holds
+        // each one's change, caused by the delete.
+        let mut instance_ids = record_wrapper
+            .prototype_instances
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+        instance_ids.retain(|instance_id| self.try_get_internal(*instance_id, true).is_some());
+
+        let mut state_inner = self.state.inner.lock().unwrap();
+        let mut changes = vec![];
+        for instance_id in &instance_ids {
+            if let Some(instance_wrapper) = state_inner.live_record(*instance_id).cloned() {
+                if instance_wrapper.prototype_id == Some(id) {
+                    let detached = Arc::from(RecordWrapper {
+                        prototype_id: None,
+                        prototype_instances: Mutex::from(
+                            instance_wrapper.prototype_instances.lock().unwrap().clone(),
+                        ),
+                        override_mask: 0,
+                        generation: instance_wrapper.generation,
+                        inner: instance_wrapper.inner.clone(),
+                    });
+                    state_inner.set_record(*instance_id, Some(detached.clone()));
+                    let lsn = self.append_change_log(
+                        &mut state_inner,
+                        *instance_id,
+                        Some(id),
+                        Some(&detached),
+                    );
+                    changes.push((*instance_id, lsn));
+                }
+            }
+            state_inner.locks[instance_id.0] = None;
+        }
+
+        state_inner.set_record(id, None);
+        state_inner.tombstones[id.0] = true;
+        state_inner.locks[id.0] = None;
+        self.state.locks_cv.notify_all();
+        let lsn = self.append_change_log(&mut state_inner, id, None, None);
+        changes.push((id, lsn));
+        drop(state_inner);
+        self.notify_changes(&changes);
+    }
+
+    fn debug_assert_registered(&self) {
+        debug_assert_eq!(
+            self.generation,
+            self.state.generation.load(Ordering::SeqCst),
+            "stale catalog handle: {} was unregistered or re-registered after checkout",
+            R::type_name()
+        );
+    }
+
+    fn write_change_log(
+        &self,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: Option<&RecordWrapper<R>>,
+        mut state_inner: MutexGuard<CatalogStateInner<R>>,
+    ) {
+        let lsn = self.append_change_log(&mut state_inner, id, cause, new_record);
+        drop(state_inner);
+        self.notify_changes(&[(id, lsn)]);
+    }
+
+    fn notify_commits(&self, commits: &Commits<R>) {
+        self.notify_changes(&commits.changes);
+        self.state.commit_observers.notify(commits);
+    }
+
+    fn notify_changes(&self, changes: &[(RecordId<R>, u64)]) {
+        for (id, lsn) in changes {
+            self.change_listeners
+                .notify(R::type_name(), id.untyped(), *lsn);
+        }
+        self.change_listeners.deliver();
+    }
+
+    fn append_change_log(
+        &self,
+        state_inner: &mut CatalogStateInner<R>,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: Option<&RecordWrapper<R>>,
+    ) -> u64 {
+        let lsn = self.sequencer.next();
+        self.append_change_log_at(state_inner, id, cause, new_record, lsn)
+    }
+
+    fn append_change_log_at(
+        &self,
+        state_inner: &mut CatalogStateInner<R>,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: Option<&RecordWrapper<R>>,
+        lsn: u64,
+    ) -> u64 {
+        state_inner.update_indexes(id, new_record.map(|new_record| &new_record.inner));
+        let new_record = new_record.map(|new_record| {
+            Arc::from(RecordWrapper {
+                prototype_id: new_record.prototype_id,
+                prototype_instances: Default::default(),
+                override_mask: new_record.override_mask,
+                generation: new_record.generation,
+                inner: new_record.inner.snapshot_for_history(),
+            })
+        });
+        let old_record = mem::replace(&mut state_inner.history[id.0], new_record.clone());
+        state_inner.versions[id.0] = lsn;
+        let change_record = ChangeRecord {
+            record_id: id,
+            cause,
+            old_record,
+            new_record,
+            lsn,
+            transaction: state_inner.transaction,
+            committed_at: Instant::now(),
+        };
+        #[cfg(feature = "serde")]
+        self.write_ahead_log.write(&self.state, &change_record);
+        self.change_listeners
+            .enqueue(|| AnyChange::from_change_record(&change_record));
+        state_inner.change_log.push(change_record);
+        state_inner.apply_history_policy();
+        lsn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Catalog;
+    use crate::{
+        change_log::Watermark,
+        proto_update_field,
+        tests::{Dog, Person},
+        EditError, IdError, Library, NetChange, Record, RecordId, ReparentError, Shutdown,
+        ValidationError,
+    };
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_on_commit_rejected() {
+        let library = Library::default();
+        library.register::<Thermostat>();
+        let catalog = library.checkout::<Thermostat>();
+        let id = catalog.create(Thermostat {
+            kelvin: 290,
+            ..Default::default()
+        });
+        let rejections = Arc::new(Mutex::new(vec![]));
+        {
+            let rejections = rejections.clone();
+            catalog
+                .on_commit_rejected(move |id, error| rejections.lock().unwrap().push((id, error)));
+        }
+
+        let error = ValidationError(String::from("kelvin can't be negative"));
+        assert_eq!(
+            Err(error.clone()),
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|thermostat| thermostat.kelvin = -1)
+        );
+        assert_eq!(
+            Ok(()),
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|thermostat| thermostat.kelvin = 300)
+        );
+        assert!(!catalog.lock_records().commit(
+            id,
+            Thermostat {
+                kelvin: -5,
+                ..Default::default()
+            }
+        ));
+
+        assert_eq!(300, catalog.get(id).kelvin);
+        assert_eq!(
+            vec![(id, error.clone()), (id, error)],
+            *rejections.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_validator() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            age: 5,
+            name: String::from("Fido"),
+            ..Default::default()
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        catalog
+            .edit(instance_id, |person| person.name = String::from("Rex"))
+            .unwrap();
+        catalog.set_validator(|person: &Person| {
+            if person.age < 0 {
+                return Err(String::from("age can't be negative"));
+            }
+            if person.name == "Rex" && person.age > 10 {
+                return Err(String::from("Rex is too old"));
+            }
+            Ok(())
+        });
+
+        assert_eq!(
+            Err(EditError::Invalid(ValidationError(String::from(
+                "age can't be negative"
+            )))),
+            catalog.edit(proto_id, |person| person.age = -1)
+        );
+        assert_eq!(
+            Err(EditError::Invalid(ValidationError(String::from(
+                "Rex is too old"
+            )))),
+            catalog.edit(proto_id, |person| person.age = 12)
+        );
+        assert_eq!(5, catalog.get(proto_id).age);
+        assert_eq!(5, catalog.get(instance_id).age);
+        assert!(!catalog.is_locked(instance_id));
+
+        assert_eq!(
+            Err(EditError::Invalid(ValidationError(String::from(
+                "Rex is too old"
+            )))),
+            catalog.transaction(&[proto_id], |records| {
+                records.get_mut(&proto_id).unwrap().age = 12;
+            })
+        );
+        assert_eq!(5, catalog.get(instance_id).age);
+
+        catalog.edit(proto_id, |person| person.age = 8).unwrap();
+        assert_eq!(8, catalog.get(instance_id).age);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let commits = Arc::new(Mutex::new(vec![]));
+        {
+            let commits = commits.clone();
+            let library = library.clone();
+            catalog.subscribe(move |id, old: Option<&Person>, new: &Person| {
+                // Reading the catalog from the callback mustn't deadlock.
+                let current_age = library.checkout::<Person>().get(id).age;
+                commits.lock().unwrap().push((
+                    id,
+                    old.map(|person| person.age),
+                    new.age,
+                    current_age,
+                ));
+            });
+        }
+
+        catalog.edit(proto_id, |person| person.age = 3).unwrap();
+        assert_eq!(
+            vec![(proto_id, Some(0), 3, 3), (instance_id, Some(0), 3, 3)],
+            *commits.lock().unwrap()
+        );
+
+        commits.lock().unwrap().clear();
+        catalog
+            .transaction(&[instance_id], |records| {
+                records.get_mut(&instance_id).unwrap().age = 4;
+            })
+            .unwrap();
+        catalog.lock_records().commit(proto_id, Person::default());
+        assert_eq!(
+            vec![
+                (instance_id, Some(3), 4, 4),
+                (proto_id, Some(3), 0, 0),
+                (instance_id, Some(4), 4, 4)
+            ],
+            *commits.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let old_ids = (0..3)
+            .map(|age| {
+                catalog.create(Person {
+                    age,
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        let start_watermark = catalog.watermark();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let _subscription = {
+            let seen = seen.clone();
+            catalog.scoped_subscribe(move |id, _| seen.lock().unwrap().push(id))
+        };
+
+        let ids = catalog
+            .replace_all(vec![
+                Person {
+                    age: 10,
+                    ..Default::default()
+                },
+                Person {
+                    age: 11,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+        assert_eq!(old_ids[..2], ids[..]);
+        assert_eq!(old_ids, *seen.lock().unwrap());
+        assert_eq!(11, catalog.get(ids[1]).age);
+        assert!(!catalog.contains(old_ids[2]));
+        assert_eq!(2, catalog.len());
+
+        let net_changes = catalog
+            .net_changes(start_watermark, catalog.watermark())
+            .into_iter()
+            .map(|net_change| match net_change {
+                NetChange::Updated(id, before, after) => (id, Some((before.age, after.age))),
+                NetChange::Deleted(id) => (id, None),
+                NetChange::Created(..) => panic!("nothing should be created"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (ids[0], Some((0, 10))),
+                (ids[1], Some((1, 11))),
+                (old_ids[2], None)
+            ],
+            net_changes
+        );
+        let transactions = catalog
+            .changes(start_watermark, catalog.watermark())
+            .map(|change| change.transaction())
+            .collect::<Vec<_>>();
+        assert!(transactions[0].is_some());
+        assert!(transactions
+            .iter()
+            .all(|transaction| *transaction == transactions[0]));
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let library = Library::default();
+        library.register::<Dog>();
+        let catalog = library.checkout::<Dog>();
+        let proto_id = catalog.create(Dog { dog_years: 3 });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let deleted_id = catalog.create(Dog::default());
+        catalog.delete(deleted_id);
+
+        assert_eq!(
+            "RecordId(0): Dog { dog_years: 3 } instances: [RecordId(1)]\n\
+             RecordId(1): Dog { dog_years: 3 } prototype: RecordId(0)\n",
+            catalog.debug_dump()
+        );
+        assert_eq!(proto_id, RecordId::new(0));
+        assert_eq!(instance_id, RecordId::new(1));
+    }
+
+    #[test]
+    fn test_prototype_instances() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let first_id = catalog.create_from_prototype(proto_id);
+        let nested_id = catalog.create_from_prototype(first_id);
+        let second_id = catalog.create_from_prototype(proto_id);
+        let deleted_id = catalog.create_from_prototype(proto_id);
+        catalog.delete(deleted_id);
+
+        let locked = catalog.lock(proto_id).unwrap();
+        assert_eq!(
+            vec![first_id, second_id],
+            catalog.prototype_instances(proto_id)
+        );
+        assert_eq!(vec![nested_id], catalog.prototype_instances(first_id));
+        assert!(catalog.prototype_instances(nested_id).is_empty());
+        assert_eq!(Some(first_id), catalog.prototype_of(nested_id));
+        assert_eq!(None, catalog.prototype_of(proto_id));
+        assert_eq!(None, catalog.prototype_of(deleted_id));
+        drop(locked);
+
+        catalog.detach_prototype(second_id);
+        assert_eq!(vec![first_id], catalog.prototype_instances(proto_id));
+    }
+
+    #[test]
+    fn test_reparent_prototype() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let old_proto_id = catalog.create(Person {
+            age: 1,
+            name: String::from("Old"),
+            fav_food: String::from("Kibble"),
+        });
+        let new_proto_id = catalog.create(Person {
+            age: 2,
+            name: String::from("New"),
+            fav_food: String::from("Pizza"),
+        });
+        let instance_id = catalog.create_from_prototype(old_proto_id);
+        catalog
+            .edit(instance_id, |person| person.name = String::from("Rex"))
+            .unwrap();
+        let nested_id = catalog.create_from_prototype(instance_id);
+
+        assert_eq!(
+            Err(ReparentError::Cycle),
+            catalog.reparent_prototype(instance_id, nested_id)
+        );
+        assert_eq!(
+            Err(ReparentError::Cycle),
+            catalog.reparent_prototype(instance_id, instance_id)
+        );
+        let deleted_id = catalog.create(Person::default());
+        catalog.delete(deleted_id);
+        assert_eq!(
+            Err(ReparentError::Missing(deleted_id.untyped())),
+            catalog.reparent_prototype(instance_id, deleted_id)
+        );
+
+        catalog
+            .reparent_prototype(instance_id, new_proto_id)
+            .unwrap();
+        assert_eq!(Some(new_proto_id), catalog.prototype_of(instance_id));
+        assert!(catalog.prototype_instances(old_proto_id).is_empty());
+        assert_eq!(vec![instance_id], catalog.prototype_instances(new_proto_id));
+        for id in [instance_id, nested_id] {
+            let person = catalog.get(id);
+            assert_eq!(
+                (2, "Rex", "Pizza"),
+                (person.age, person.name.as_str(), person.fav_food.as_str())
+            );
+        }
+        catalog.edit(old_proto_id, |person| person.age = 5).unwrap();
+        catalog.edit(new_proto_id, |person| person.age = 3).unwrap();
+        assert_eq!(3, catalog.get(nested_id).age);
+
+        let standalone_id = catalog.create(Person {
+            age: 9,
+            fav_food: String::from("Pizza"),
+            ..Default::default()
+        });
+        catalog
+            .reparent_prototype(standalone_id, new_proto_id)
+            .unwrap();
+        assert_eq!(9, catalog.get(standalone_id).age);
+        assert_eq!(
+            vec!["age", "name"],
+            catalog.overridden_fields(standalone_id)
+        );
+
+        let bones_proto_id = catalog.create(Person {
+            fav_food: String::from("Bones"),
+            ..Default::default()
+        });
+        catalog.set_validator(|person: &Person| match person.fav_food.as_str() {
+            "Bones" if person.name == "Rex" => Err(String::from("Rex doesn't like bones")),
+            _ => Ok(()),
+        });
+        assert_eq!(
+            Err(ReparentError::Invalid(ValidationError(String::from(
+                "Rex doesn't like bones"
+            )))),
+            catalog.reparent_prototype(instance_id, bones_proto_id)
+        );
+        assert_eq!(Some(new_proto_id), catalog.prototype_of(instance_id));
+        assert_eq!("Pizza", catalog.get(nested_id).fav_food);
+        for id in [new_proto_id, bones_proto_id, instance_id, nested_id] {
+            assert!(!catalog.is_locked(id));
+        }
+    }
+
+    #[test]
+    fn test_detach_prototype() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        catalog.edit(proto_id, |person| person.age = 2).unwrap();
+
+        catalog.detach_prototype(instance_id);
+        assert!(!catalog.is_instance(instance_id));
+        assert!(catalog.overridden_fields(instance_id).is_empty());
+        catalog.edit(proto_id, |person| person.age = 6).unwrap();
+        assert_eq!(2, catalog.get(instance_id).age);
+        assert!(!catalog.is_prototype(proto_id));
+    }
+
+    #[test]
+    fn test_promote_to_prototype() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let promoted_id = catalog.create_from_prototype(proto_id);
+        catalog
+            .edit(promoted_id, |person| person.name = String::from("Rex"))
+            .unwrap();
+
+        assert_eq!(promoted_id, catalog.promote_to_prototype(promoted_id));
+        assert!(!catalog.is_instance(promoted_id));
+        assert!(!catalog.is_prototype(proto_id));
+        assert_eq!("Rex", catalog.get(promoted_id).name);
+
+        let instance_id = catalog.create_from_prototype(promoted_id);
+        catalog.edit(proto_id, |person| person.age = 4).unwrap();
+        assert_eq!(0, catalog.get(promoted_id).age);
+        catalog.edit(promoted_id, |person| person.age = 7).unwrap();
+        assert_eq!(7, catalog.get(instance_id).age);
+        assert_eq!("Rex", catalog.get(instance_id).name);
+        assert!(!catalog.is_locked(proto_id));
+        assert!(!catalog.is_locked(promoted_id));
+    }
+
+    #[test]
+    fn test_commit_if_changed() {
+        let library = Library::default();
+        library.register::<Thermostat>();
+        let catalog = library.checkout::<Thermostat>();
+        let id = catalog.create(Thermostat {
+            kelvin: 290,
+            read_count: 0,
+        });
+        let start_watermark = catalog.watermark();
+
+        let locked = catalog.lock(id).unwrap();
+        assert_eq!(
+            Ok(false),
+            catalog.commit_if_changed(
+                &locked,
+                Thermostat {
+                    kelvin: 290,
+                    read_count: 7,
+                },
+            )
+        );
+        assert_eq!(0, catalog.distance(start_watermark));
+        assert_eq!(
+            Ok(true),
+            catalog.commit_if_changed(
+                &locked,
+                Thermostat {
+                    kelvin: 295,
+                    read_count: 7,
+                },
+            )
+        );
+        assert_eq!(1, catalog.distance(start_watermark));
+        drop(locked);
+        assert_eq!(295, catalog.get(id).kelvin);
+    }
+
+    #[test]
+    fn test_commit_inherited() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let start_watermark = catalog.watermark();
+
+        let locked = catalog.lock(instance_id).unwrap();
+        catalog
+            .commit_inherited(
+                &locked,
+                Person {
+                    age: 5,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        drop(locked);
+        assert_eq!(5, catalog.get(instance_id).age);
+        assert!(catalog.overridden_fields(instance_id).is_empty());
+        let change = catalog
+            .changes(start_watermark, catalog.watermark())
+            .next()
+            .unwrap();
+        assert_eq!(Some(proto_id), change.cause());
+    }
+
+    #[test]
+    fn test_commit_if_unchanged() {
+        let library = Library::default();
+        library.register::<Thermostat>();
+        let catalog = library.checkout::<Thermostat>();
+        let id = catalog.create(Thermostat {
+            kelvin: 290,
+            read_count: 0,
+        });
+
+        let expected = catalog.peek(id);
+        catalog
+            .edit(id, |thermostat| thermostat.kelvin = 300)
+            .unwrap();
+        let new_record = Thermostat {
+            kelvin: expected.kelvin + 5,
+            ..expected.clone()
+        };
+        let locked = catalog.lock(id).unwrap();
+        assert_eq!(
+            Err(new_record.clone()),
+            catalog.commit_if_unchanged(&locked, &expected, new_record)
+        );
+        assert_eq!(300, catalog.get(id).kelvin);
+
+        let expected = catalog.peek(id);
+        let new_record = Thermostat {
+            kelvin: expected.kelvin + 5,
+            ..expected.clone()
+        };
+        assert_eq!(
+            Ok(()),
+            catalog.commit_if_unchanged(&locked, &expected, new_record)
+        );
+        assert_eq!(
+            Err(Thermostat {
+                kelvin: -1,
+                read_count: 0
+            }),
+            catalog.commit_if_unchanged(
+                &locked,
+                &catalog.peek(id),
+                Thermostat {
+                    kelvin: -1,
+                    read_count: 0
+                }
+            )
+        );
+        drop(locked);
+        assert_eq!(305, catalog.get(id).kelvin);
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Thermostat {
+        kelvin: i32,
+        read_count: u32,
+    }
+    impl Record for Thermostat {
+        fn type_name() -> &'static str {
+            "Thermostat"
+        }
+
+        fn proto_update(&self, old: &Thermostat, new: &Thermostat) -> Thermostat {
+            Thermostat {
+                kelvin: *proto_update_field(&self.kelvin, &old.kelvin, &new.kelvin),
+                read_count: self.read_count,
+            }
+        }
+
+        fn content_eq(&self, other: &Thermostat) -> bool
+        where
+            Self: PartialEq,
+        {
+            self.kelvin == other.kelvin
+        }
+
+        fn validate(&self) -> Result<(), ValidationError> {
+            if self.kelvin < 0 {
+                return Err(ValidationError(String::from("kelvin can't be negative")));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Kennel {
+        dog_ids: Vec<RecordId<Dog>>,
+    }
+    impl Record for Kennel {
+        fn type_name() -> &'static str {
+            "Kennel"
+        }
+
+        fn proto_update(&self, old: &Kennel, new: &Kennel) -> Kennel {
+            Kennel {
+                dog_ids: proto_update_field(&self.dog_ids, &old.dog_ids, &new.dog_ids).clone(),
+            }
+        }
+
+        fn referenced_ids(&self) -> Vec<(&'static str, RecordId)> {
+            self.dog_ids
+                .iter()
+                .map(|dog_id| ("Dog", dog_id.untyped()))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_create_with_id() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+
+        let id = RecordId::new(5);
+        let person = Person {
+            age: 5,
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), catalog.create_with_id(id, person.clone()));
+        assert_eq!(5, catalog.get(id).age);
+        assert_eq!(
+            Err(IdError::Occupied(id.untyped())),
+            catalog.create_with_id(id, person)
+        );
+        assert_eq!(Some(&1), library.summary().records_per_type.get("Person"));
+
+        assert_eq!(
+            Ok(()),
+            catalog.create_with_id(RecordId::new(2), Person::default())
+        );
+        assert_eq!(RecordId::new(6), catalog.create(Person::default()));
+    }
+
+    #[test]
+    fn test_invalid_id() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+
+        assert!(id.is_valid());
+        assert!(!RecordId::<Person>::INVALID.is_valid());
+        assert!(catalog.get_checked(id).is_some());
+        assert!(catalog.get_checked(RecordId::INVALID).is_none());
+        assert!(catalog.get_checked(RecordId::new(9999)).is_none());
+        catalog.delete(id);
+        assert!(catalog.get_checked(id).is_none());
+        assert_eq!(
+            Err(IdError::Invalid),
+            catalog.create_with_id(RecordId::INVALID, Person::default())
+        );
+    }
+
+    #[test]
+    fn test_get_or_default() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 7,
+            ..Default::default()
+        });
+
+        assert_eq!(7, catalog.get_or_default(id).age);
+        assert_eq!(0, catalog.get_or_default(RecordId::INVALID).age);
+        assert_eq!(0, catalog.get_or_default(RecordId::new(9999)).age);
+        catalog.delete(id);
+        assert_eq!(0, catalog.get_or_default(id).age);
+    }
+
+    #[test]
+    #[should_panic(expected = "Person record RecordId(1) was deleted")]
+    fn test_get_deleted() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        catalog.create(Person::default());
+        let id = catalog.create(Person::default());
+        catalog.delete(id);
+
+        assert_eq!(RecordId::new(2), catalog.create(Person::default()));
+        catalog.get(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Person record RecordId(3) does not exist")]
+    fn test_get_gap() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        catalog
+            .create_with_id(RecordId::new(5), Person::default())
+            .unwrap();
+        catalog.get(RecordId::new(3));
+    }
+
+    #[test]
+    fn test_retain() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = (0..5)
+            .map(|age| {
+                catalog.create(Person {
+                    age,
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        let start_watermark = catalog.watermark();
+
+        assert_eq!(3, catalog.retain(|person| person.age % 2 == 1));
+
+        assert!(!catalog.contains(ids[0]));
+        assert_eq!(1, catalog.get(ids[1]).age);
+        assert!(!catalog.contains(ids[2]));
+        assert_eq!(3, catalog.get(ids[3]).age);
+        assert!(!catalog.contains(ids[4]));
+
+        let changes = catalog
+            .changes(start_watermark, catalog.watermark())
+            .collect::<Vec<_>>();
+        assert_eq!(3, changes.len());
+        assert!(changes.iter().all(|change| change.new_record().is_none()));
+    }
+
+    #[test]
+    fn test_delete_prototype_detaches_instances() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let grandmother_id = catalog.create(Person::default());
+        let mother_id = catalog.create_from_prototype(grandmother_id);
+        let daughter_id = catalog.create_from_prototype(mother_id);
+
+        catalog.delete(mother_id);
+        assert!(!catalog.contains(mother_id));
+
+        catalog
+            .lock(grandmother_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Grandma"))
+            .unwrap();
+        assert_eq!(String::default(), catalog.get(daughter_id).name);
+
+        catalog.delete(grandmother_id);
+        catalog
+            .lock(daughter_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Daughter"))
+            .unwrap();
+        assert_eq!(String::from("Daughter"), catalog.get(daughter_id).name);
+    }
+
+    #[test]
+    fn test_get_versioned() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+
+        let (person, version) = catalog.get_versioned(id).unwrap();
+        assert_eq!(0, person.age);
+
+        catalog
+            .lock(id)
+            .unwrap()
+            .mutate(|person| person.age = 1)
+            .unwrap();
+        let (person, new_version) = catalog.get_versioned(id).unwrap();
+        assert_eq!(1, person.age);
+        assert!(new_version > version);
+
+        catalog.delete(id);
+        assert!(catalog.get_versioned(id).is_none());
+        assert!(catalog.get_versioned(RecordId::new(10)).is_none());
+    }
+
+    #[test]
+    fn test_iter() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        for age in 0..3 {
+            catalog.create(Person {
+                age,
+                ..Default::default()
+            });
+        }
+        catalog.delete(RecordId::new(1));
+
+        let records = catalog.iter();
+        catalog
+            .lock(RecordId::new(0))
+            .unwrap()
+            .mutate(|person| person.age = 10)
+            .unwrap();
+        assert_eq!(
+            vec![(RecordId::new(0), 0), (RecordId::new(2), 2)],
+            records
+                .map(|(id, person)| (id, person.age))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = catalog.create_many(vec![Person::default(); 3]);
+        catalog.delete(ids[1]);
+
+        let snapshot = catalog.snapshot();
+        catalog.edit(ids[0], |person| person.age = 10).unwrap();
+        catalog.delete(ids[2]);
+        let read_count = catalog.reads.lock().unwrap().len();
+        assert_eq!(2, snapshot.len());
+        assert_eq!(Some(0), snapshot.get(ids[0]).map(|person| person.age));
+        assert!(snapshot.get(ids[1]).is_none());
+        assert_eq!(
+            vec![ids[0], ids[2]],
+            snapshot.iter().map(|(id, _)| id).collect::<Vec<_>>()
+        );
+        assert_eq!(read_count, catalog.reads.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_edit() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let start_watermark = catalog.watermark();
+
+        catalog.edit(proto_id, |person| person.age = 5).unwrap();
+        assert_eq!(5, catalog.get(instance_id).age);
+        assert_eq!(
+            vec![(proto_id, None), (instance_id, Some(proto_id))],
+            catalog
+                .changes(start_watermark, catalog.watermark())
+                .map(|change| (change.record_id(), change.cause()))
+                .collect::<Vec<_>>()
+        );
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catalog.edit(proto_id, |_| panic!("edit failed")).unwrap();
+        }));
+        assert!(result.is_err());
+        assert!(!catalog.is_locked(proto_id));
+        assert_eq!(5, catalog.get(proto_id).age);
+    }
+
+    #[test]
+    fn test_subscribe_filtered() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let catalog = library.checkout::<Person>();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let subscription = catalog.subscribe_filtered(|person| person.age >= 18, {
+            let seen = seen.clone();
+            move |id, _| seen.lock().unwrap().push(id)
+        });
+
+        let adult_id = catalog.create(Person {
+            age: 30,
+            ..Default::default()
+        });
+        let child_id = catalog.create(Person {
+            age: 8,
+            ..Default::default()
+        });
+        catalog.edit(child_id, |person| person.age = 9).unwrap();
+        catalog.edit(adult_id, |person| person.age = 31).unwrap();
+        library.checkout::<Dog>().create(Dog::default());
+        catalog.delete(adult_id);
+        assert_eq!(vec![adult_id, adult_id], *seen.lock().unwrap());
+
+        catalog.edit(child_id, |person| person.age = 18).unwrap();
+        catalog.edit(child_id, |person| person.age = 10).unwrap();
+        assert_eq!(vec![adult_id, adult_id, child_id], *seen.lock().unwrap());
+
+        drop(subscription);
+        catalog.edit(child_id, |person| person.age = 40).unwrap();
+        assert_eq!(3, seen.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_clone_record() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        catalog
+            .edit(instance_id, |person| person.name = String::from("Eva"))
+            .unwrap();
+
+        let clone_id = catalog.clone_record(instance_id);
+        let proto_clone_id = catalog.clone_record(proto_id);
+        catalog.edit(proto_id, |person| person.age = 40).unwrap();
+
+        assert!(catalog.is_instance(clone_id));
+        assert_eq!(40, catalog.get(clone_id).age);
+        assert_eq!(String::from("Eva"), catalog.get(clone_id).name);
+        assert!(!catalog.is_instance(proto_clone_id));
+        assert!(!catalog.is_prototype(proto_clone_id));
+        assert_eq!(0, catalog.get(proto_clone_id).age);
+
+        // An override that happens to match the prototype is still an override on the copy.
+        catalog
+            .edit(proto_id, |person| person.name = String::from("Eva"))
+            .unwrap();
+        let matching_clone_id = catalog.clone_record(instance_id);
+        assert_eq!(vec!["name"], catalog.overridden_fields(instance_id));
+        assert_eq!(vec!["name"], catalog.overridden_fields(matching_clone_id));
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        let (locked_sender, locked_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+
+        let holder = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                let _locked = catalog.lock(id).unwrap();
+                locked_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            })
+        };
+        locked_receiver.recv().unwrap();
+        assert!(catalog.try_lock(id).is_none());
+
+        release_sender.send(()).unwrap();
+        holder.join().unwrap();
+        let locked = catalog.try_lock(id).unwrap();
+        assert!(catalog.try_lock(id).is_none());
+        locked.mutate(|person| person.age = 2).unwrap();
+        drop(locked);
+        assert!(!catalog.is_locked(id));
+        assert_eq!(2, catalog.get(id).age);
+        assert!(catalog.try_lock(RecordId::new(9999)).is_none());
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        let (locked_sender, locked_receiver) = mpsc::channel();
+
+        let holder = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                let _locked = catalog.lock(id).unwrap();
+                locked_sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            })
+        };
+        locked_receiver.recv().unwrap();
+        assert!(catalog
+            .lock_timeout(id, Duration::from_millis(10))
+            .is_none());
+
+        let locked = catalog.lock_timeout(id, Duration::from_secs(5)).unwrap();
+        holder.join().unwrap();
+        locked.mutate(|person| person.age = 3).unwrap();
+        drop(locked);
+        assert_eq!(3, catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        let locked = catalog.lock(id).unwrap();
+        let (waiting_sender, waiting_receiver) = mpsc::channel();
+
+        let waiter = {
+            let library = library.clone();
+            let waiting_sender = waiting_sender.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                waiting_sender.send(()).unwrap();
+                catalog.lock(id).map(|_| ())
+            })
+        };
+        let replacer = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                waiting_sender.send(()).unwrap();
+                catalog.replace_all(vec![Person::default()]).map(|_| ())
+            })
+        };
+        waiting_receiver.recv().unwrap();
+        waiting_receiver.recv().unwrap();
+        catalog.shutdown();
+        assert_eq!(Err(Shutdown), waiter.join().unwrap());
+        assert_eq!(Err(Shutdown), replacer.join().unwrap());
+
+        locked.mutate(|person| person.age = 2).unwrap();
+        drop(locked);
+        assert_eq!(2, catalog.get(id).age);
+        assert!(catalog.lock(id).is_err());
+        assert!(catalog.try_lock(id).is_none());
+        assert!(catalog.lock_timeout(id, Duration::from_millis(1)).is_none());
+        assert_eq!(
+            Err(EditError::Shutdown),
+            catalog.edit(id, |person| person.age = 3)
+        );
+        assert_eq!(Err(EditError::Shutdown), catalog.transaction(&[id], |_| {}));
+    }
+
+    #[test]
+    fn test_find_dangling() {
+        let library = Library::default();
+        library.register::<Dog>();
+        library.register::<Kennel>();
+        let dogs = library.checkout::<Dog>();
+        let kennels = library.checkout::<Kennel>();
+        let rex_id = dogs.create(Dog::default());
+        let fido_id = dogs.create(Dog::default());
+        let kennel_id = kennels.create(Kennel {
+            dog_ids: vec![rex_id, fido_id],
+        });
+        kennels.create(Kennel {
+            dog_ids: vec![rex_id],
+        });
+        assert!(kennels.find_dangling().is_empty());
+
+        dogs.delete(fido_id);
+        assert_eq!(
+            vec![(kennel_id, vec![("Dog", fido_id.untyped())])],
+            kennels.find_dangling()
+        );
+    }
+
+    #[test]
+    fn test_transaction() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let other_id = catalog.create(Person::default());
+        let start_watermark = catalog.watermark();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catalog
+                .transaction(&[other_id, proto_id], |people| {
+                    people.get_mut(&proto_id).unwrap().age = 1;
+                    panic!("transaction failed");
+                })
+                .unwrap();
+        }));
+        assert!(result.is_err());
+        assert_eq!(0, catalog.distance(start_watermark));
+        assert!(!catalog.is_locked(proto_id));
+
+        catalog
+            .transaction(&[other_id, instance_id, proto_id], |people| {
+                people.get_mut(&proto_id).unwrap().age = 2;
+                people.get_mut(&other_id).unwrap().age = 3;
+                people.remove(&instance_id);
+            })
+            .unwrap();
+        assert_eq!(2, catalog.get(instance_id).age);
+        assert_eq!(3, catalog.get(other_id).age);
+        assert!(!catalog.is_locked(instance_id));
+        let changes = catalog
+            .changes(start_watermark, catalog.watermark())
+            .map(|change| (change.record_id(), change.transaction()))
+            .collect::<Vec<_>>();
+        let transaction = changes[0].1;
+        assert!(transaction.is_some());
+        assert_eq!(
+            vec![
+                (proto_id, transaction),
+                (instance_id, transaction),
+                (other_id, transaction)
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn test_commit_with_lsn() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let first_id = catalog.create(Person::default());
+        let second_id = catalog.create(Person::default());
+        catalog.edit(second_id, |person| person.age = 7).unwrap();
+        catalog.edit(first_id, |person| person.age = 3).unwrap();
+        let start_watermark = catalog.watermark();
+        let summarize = |catalog: &Catalog<Person>, start_watermark: Watermark| {
+            catalog
+                .changes(start_watermark, catalog.watermark())
+                .map(|change| {
+                    (
+                        change.record_id(),
+                        change.lsn(),
+                        change.old_record().map(|person| person.age),
+                        change.new_record().map(|person| person.age),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let original_changes = summarize(&catalog, Watermark(0));
+
+        let replayed_library = Library::default();
+        replayed_library.register::<Person>();
+        let replayed_catalog = replayed_library.checkout::<Person>();
+        for change in catalog.changes(Watermark(0), start_watermark) {
+            let id = change.record_id();
+            match change.old_record() {
+                None => replayed_catalog
+                    .create_with_lsn(id, change.new_record().unwrap().clone(), change.lsn())
+                    .unwrap(),
+                Some(_) => replayed_catalog
+                    .commit_with_lsn(
+                        &replayed_catalog.lock(id).unwrap(),
+                        change.new_record().unwrap().clone(),
+                        change.lsn(),
+                    )
+                    .unwrap(),
+            }
+        }
+
+        assert_eq!(original_changes, summarize(&replayed_catalog, Watermark(0)));
+        let id = replayed_catalog.create(Person::default());
+        assert_eq!(
+            Some(original_changes.last().unwrap().1 + 1),
+            replayed_catalog.get_versioned(id).map(|(_, lsn)| lsn)
+        );
+    }
+
+    #[test]
+    fn test_scoped_subscribe() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let catalog = library.checkout::<Person>();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let subscription = catalog.scoped_subscribe({
+            let seen = seen.clone();
+            move |id, _| seen.lock().unwrap().push(id)
+        });
+
+        let id = catalog.create(Person::default());
+        library.checkout::<Dog>().create(Dog::default());
+        drop(subscription);
+        catalog.edit(id, |person| person.age = 1).unwrap();
+
+        assert_eq!(vec![id], *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn test_create_many() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let first_id = catalog.create(Person::default());
+        let start_watermark = catalog.watermark();
+
+        let ids = catalog.create_many(
+            (1..=3)
+                .map(|age| Person {
+                    age,
+                    ..Default::default()
+                })
+                .collect(),
+        );
+
+        assert_eq!(
+            vec![
+                RecordId::new(first_id.index() + 1),
+                RecordId::new(first_id.index() + 2),
+                RecordId::new(first_id.index() + 3)
+            ],
+            ids
+        );
+        assert_eq!(
+            vec![1, 2, 3],
+            ids.iter()
+                .map(|id| catalog.get(*id).age)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(3, catalog.distance(start_watermark));
+    }
+
+    #[test]
+    fn test_touch() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 3,
+            ..Default::default()
+        });
+        let seen = Arc::new(Mutex::new(vec![]));
+        library.on_any_change({
+            let seen = seen.clone();
+            move |_, id, lsn| seen.lock().unwrap().push((id, lsn))
+        });
+        let (_, version) = catalog.get_versioned(id).unwrap();
+        let start_watermark = catalog.watermark();
+
+        assert!(catalog.touch(id));
+
+        let (person, new_version) = catalog.get_versioned(id).unwrap();
+        assert_eq!(3, person.age);
+        assert!(new_version > version);
+        assert_eq!(vec![(id.untyped(), new_version)], *seen.lock().unwrap());
+        let change = catalog
+            .changes(start_watermark, catalog.watermark())
+            .next()
+            .unwrap();
+        assert_eq!(Some(3), change.old_record().map(|person| person.age));
+        assert_eq!(Some(3), change.new_record().map(|person| person.age));
+
+        catalog.delete(id);
+        let watermark = catalog.watermark();
+        assert!(!catalog.touch(id));
+        assert!(!catalog.touch(RecordId::new(5)));
+        assert_eq!(watermark, catalog.watermark());
+        catalog.create(Person::default());
+    }
+
+    #[test]
+    fn test_cascade_histogram() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let standalone_id = catalog.create(Person::default());
+        let grandmother_id = catalog.create(Person::default());
+        let mother_id = catalog.create_from_prototype(grandmother_id);
+        catalog.create_from_prototype(mother_id);
+
+        for age in 0..3 {
+            catalog
+                .lock(standalone_id)
+                .unwrap()
+                .mutate(|person| person.age = age)
+                .unwrap();
+        }
+        catalog
+            .lock(mother_id)
+            .unwrap()
+            .mutate(|person| person.age = 1)
+            .unwrap();
+        catalog
+            .lock(grandmother_id)
+            .unwrap()
+            .mutate(|person| person.age = 2)
+            .unwrap();
+
+        assert_eq!(vec![(1, 3), (2, 1), (3, 1)], catalog.cascade_histogram());
+    }
+
+    #[test]
+    fn test_try_get_skips_locked() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        assert!(catalog.try_get(id).is_some());
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let thread = thread::spawn({
+            let library_copy = library.clone();
+            move || {
+                let catalog = library_copy.checkout::<Person>();
+                let _person = catalog.lock(id).unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+        });
+
+        locked_rx.recv().unwrap();
+        assert!(catalog.try_get(id).is_none());
+
+        release_tx.send(()).unwrap();
+        thread.join().unwrap();
+        assert!(catalog.try_get(id).is_some());
+    }
+
+    #[test]
+    fn test_diff_against_prototype() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+
+        assert!(catalog.diff_against_prototype(instance_id).is_empty());
+        assert!(catalog.diff_against_prototype(proto_id).is_empty());
+
+        catalog
+            .lock(instance_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Eva"))
+            .unwrap();
+        assert_eq!(vec!["name"], catalog.diff_against_prototype(instance_id));
+
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Eva"))
+            .unwrap();
+        assert!(catalog.diff_against_prototype(instance_id).is_empty());
+
+        catalog.delete(instance_id);
+        assert!(catalog.diff_against_prototype(instance_id).is_empty());
+        catalog.create(Person::default());
+    }
+
+    #[test]
+    fn test_lock_records() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let other_id = catalog.create(Person {
+            age: 40,
+            ..Default::default()
+        });
+        let start_watermark = catalog.watermark();
+
+        {
+            let mut records = catalog.lock_records();
+            let oldest_age = records.iter().map(|(_, person)| person.age).max().unwrap();
+            let mut write = records.get(proto_id).unwrap().clone();
+            write.age = oldest_age + 1;
+            assert!(records.commit(proto_id, write));
+            assert_eq!(41, records.get(instance_id).unwrap().age);
+        }
+
+        assert_eq!(41, catalog.get(proto_id).age);
+        assert_eq!(41, catalog.get(instance_id).age);
+        assert_eq!(40, catalog.get(other_id).age);
+        assert_eq!(
+            2,
+            catalog
+                .changes(start_watermark, catalog.watermark())
+                .count()
+        );
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let thread = thread::spawn({
+            let library_copy = library.clone();
+            move || {
+                let catalog = library_copy.checkout::<Person>();
+                let _person = catalog.lock(instance_id).unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+        });
+
+        locked_rx.recv().unwrap();
+        assert!(!catalog.lock_records().commit(proto_id, Person::default()));
+        release_tx.send(()).unwrap();
+        thread.join().unwrap();
+        assert_eq!(41, catalog.get(instance_id).age);
+    }
+
+    #[test]
+    fn test_lock_records_rejected() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        catalog.set_validator(|person: &Person| match person.age {
+            age if age < 0 => Err(String::from("age can't be negative")),
+            _ => Ok(()),
+        });
+        let rejected_ages = Arc::new(Mutex::new(vec![]));
+        catalog.on_commit_rejected({
+            let library = library.clone();
+            let rejected_ages = rejected_ages.clone();
+            move |id, _| {
+                let age = library.checkout::<Person>().get(id).age;
+                rejected_ages.lock().unwrap().push(age);
+            }
+        });
+
+        {
+            let mut records = catalog.lock_records();
+            assert!(!records.commit(
+                id,
+                Person {
+                    age: -1,
+                    ..Default::default()
+                }
+            ));
+            assert!(rejected_ages.lock().unwrap().is_empty());
+        }
+        assert_eq!(vec![0], *rejected_ages.lock().unwrap());
+    }
+
+    #[test]
+    fn test_create_from_prototype_with() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            age: 20,
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let instance_id = catalog
+            .create_from_prototype_with(proto_id, |person| person.name = String::from("Eva"));
+
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| {
+                person.age = 21;
+                person.name = String::from("Adam");
+            })
+            .unwrap();
+
+        assert_eq!(21, catalog.get(instance_id).age);
+        assert_eq!(String::from("Eva"), catalog.get(instance_id).name);
+        assert!(catalog.is_instance(instance_id));
+    }
+
+    #[cfg(feature = "wrapper_pool")]
+    #[test]
+    fn test_wrapper_pool() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        let commit_age = |age| {
+            catalog.lock_records().commit(
+                id,
+                Person {
+                    age,
+                    ..Default::default()
+                },
+            );
+            Arc::as_ptr(&catalog.get(id).record_wrapper)
+        };
+
+        let first = commit_age(1);
+        let held = catalog.get(id);
+        assert_ne!(first, commit_age(2));
+        assert_ne!(first, commit_age(3));
+        drop(held);
+        assert_eq!(first, commit_age(4));
+        assert_eq!(4, catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_get_many_versioned() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+
+        let writer = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                for age in 1..=200 {
+                    catalog
+                        .lock(proto_id)
+                        .unwrap()
+                        .mutate(|person| person.age = age)
+                        .unwrap();
+                }
+            })
+        };
+        for _ in 0..200 {
+            let records = catalog.get_many_versioned(&[proto_id, instance_id]);
+            let (proto, proto_version) = records[0].as_ref().unwrap();
+            let (instance, instance_version) = records[1].as_ref().unwrap();
+            assert_eq!(proto.age, instance.age);
+            assert!(proto_version < instance_version);
+        }
+        writer.join().unwrap();
+
+        catalog.delete(instance_id);
+        let records = catalog.get_many_versioned(&[instance_id, proto_id, RecordId::new(9)]);
+        assert!(records[0].is_none());
+        assert_eq!(200, records[1].as_ref().unwrap().0.age);
+        assert!(records[2].is_none());
+        catalog.create(Person::default());
+    }
+
+    #[test]
+    fn test_get_many() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = catalog.create_many(vec![
+            Person {
+                age: 1,
+                ..Default::default()
+            },
+            Person {
+                age: 2,
+                ..Default::default()
+            },
+        ]);
+
+        let records = catalog.get_many(&[ids[1], ids[0], ids[1]]);
+        let ages: Vec<i32> = records.iter().map(|person| person.age).collect();
+        assert_eq!(vec![2, 1, 2], ages);
+
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            catalog.get_many(&[ids[0], RecordId::new(2)]);
+        }))
+        .unwrap_err();
+        assert_eq!(
+            "Person record RecordId(2) does not exist",
+            *panic.downcast::<String>().unwrap()
+        );
+        catalog.create(Person::default());
+        assert_eq!(3, catalog.len());
+    }
+
+    #[test]
+    fn test_delete_swap() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let removed_id = catalog.create(Person::default());
+        let tail_id = catalog.create(Person::default());
+        let last_id = catalog.create_from_prototype(proto_id);
+        catalog
+            .lock(last_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Last"))
+            .unwrap();
+
+        let (moved_from, moved_to) = catalog.delete_swap(removed_id).unwrap();
+        assert_eq!(last_id, moved_from);
+        assert_eq!(removed_id.index(), moved_to.index());
+        assert!(!catalog.contains(last_id));
+        assert!(!catalog.contains(removed_id));
+        assert_eq!(String::from("Last"), catalog.get(moved_to).name);
+        assert!(catalog.is_instance(moved_to));
+
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 9)
+            .unwrap();
+        assert_eq!(9, catalog.get(moved_to).age);
+        let refilled_id = catalog.create(Person::default());
+        assert_eq!(last_id.index(), refilled_id.index());
+        assert!(!catalog.contains(last_id));
+
+        catalog.delete(refilled_id);
+        assert_eq!(None, catalog.delete_swap(tail_id));
+        let refilled_id = catalog.create(Person::default());
+        assert_eq!(tail_id.index(), refilled_id.index());
+        assert_ne!(tail_id, refilled_id);
+
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let removed_id = catalog.create(Person::default());
+        let moved_proto_id = catalog.create(Person::default());
+        catalog
+            .reparent_prototype(instance_id, moved_proto_id)
+            .unwrap();
+        let start_watermark = catalog.watermark();
+        let (moved_from, moved_to) = catalog.delete_swap(removed_id).unwrap();
+        assert_eq!(moved_proto_id, moved_from);
+        assert_eq!(Some(moved_to), catalog.prototype_of(instance_id));
+        assert_eq!(vec![instance_id], catalog.prototype_instances(moved_to));
+        let relinked = catalog
+            .changes(start_watermark, catalog.watermark())
+            .find(|change| change.record_id() == instance_id)
+            .unwrap();
+        assert_eq!(Some(moved_to), relinked.cause());
+        catalog.edit(moved_to, |person| person.age = 12).unwrap();
+        assert_eq!(12, catalog.get(instance_id).age);
+    }
+
+    #[test]
+    #[should_panic(expected = "Person record RecordId(0) was deleted")]
+    fn test_stale_id() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = catalog.create_many(vec![Person::default(); 3]);
+        let (_, moved_id) = catalog.delete_swap(ids[0]).unwrap();
+        let refilled_id = catalog.create(Person::default());
+
+        assert_eq!(1, moved_id.generation());
+        assert_eq!(1, refilled_id.generation());
+        assert!(catalog.get_checked(ids[2]).is_none());
+        assert!(catalog.try_get(ids[0]).is_none());
+        assert!(catalog.try_lock(ids[0]).is_none());
+        assert_eq!(0, catalog.get_or_default(ids[0]).age);
+        assert_eq!(
+            vec![moved_id, ids[1], refilled_id],
+            catalog.iter().map(|(id, _)| id).collect::<Vec<_>>()
+        );
+        catalog.lock(ids[0]).unwrap();
+    }
+
+    #[test]
+    fn test_count_where() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        for age in 0..10 {
+            catalog.create(Person {
+                age,
+                ..Default::default()
+            });
+        }
+        catalog.delete(RecordId::new(9));
+
+        assert_eq!(4, catalog.count_where(|person| person.age >= 5));
+        assert_eq!(0, catalog.count_where(|person| person.age > 100));
+    }
+
+    #[test]
+    fn test_bulk_create_from_prototype() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            age: 3,
+            ..Default::default()
+        });
+        let instance_ids = catalog.bulk_create_from_prototype(proto_id, 100);
+        assert_eq!(100, instance_ids.len());
+        assert!(!catalog.is_locked(proto_id));
+
+        catalog.edit(proto_id, |person| person.age = 4).unwrap();
+        for instance_id in &instance_ids {
+            assert!(catalog.is_instance(*instance_id));
+            assert_eq!(4, catalog.get(*instance_id).age);
+        }
+        assert_eq!(101, catalog.len());
+    }
+
+    #[test]
+    fn test_find() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = (0..5)
+            .map(|age| {
+                catalog.create(Person {
+                    age,
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        catalog.delete(ids[3]);
+
+        assert_eq!(vec![ids[2], ids[4]], catalog.find(|person| person.age >= 2));
+        assert_eq!(Some(ids[2]), catalog.find_first(|person| person.age >= 2));
+        assert_eq!(None, catalog.find_first(|person| person.age == 3));
+        assert!(catalog.find(|person| person.age > 100).is_empty());
+    }
+
+    #[test]
+    fn test_len() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        assert!(catalog.is_empty());
+        let ids = (0..3)
+            .map(|_| catalog.create(Person::default()))
+            .collect::<Vec<_>>();
+        catalog.delete(ids[1]);
+
+        assert_eq!(2, catalog.len());
+        assert!(!catalog.is_empty());
+    }
+
+    #[test]
+    fn test_cascade_order() {
+        let run_edit = || {
+            let library = Library::default();
+            library.register::<Person>();
+            let catalog = library.checkout::<Person>();
+            let proto_id = catalog.create(Person::default());
+            let mother_id = catalog.create_from_prototype(proto_id);
+            for _ in 0..8 {
+                catalog.create_from_prototype(proto_id);
+            }
+            catalog.create_from_prototype(mother_id);
+            let start_watermark = catalog.watermark();
+            catalog
+                .lock(proto_id)
+                .unwrap()
+                .mutate(|person| person.age = 1)
+                .unwrap();
+            catalog
+                .changes(start_watermark, catalog.watermark())
+                .map(|change| (change.record_id().0, change.lsn()))
+                .collect::<Vec<_>>()
+        };
+
+        let changes = run_edit();
+        assert_eq!(
+            vec![0, 1, 10, 2, 3, 4, 5, 6, 7, 8, 9],
+            changes.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(changes, run_edit());
+    }
+
+    #[test]
+    fn test_is_prototype_is_instance() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let grandmother_id = catalog.create(Person::default());
+        let mother_id = catalog.create_from_prototype(grandmother_id);
+        let daughter_id = catalog.create_from_prototype(mother_id);
+
+        assert!(catalog.is_prototype(grandmother_id));
+        assert!(!catalog.is_instance(grandmother_id));
+        assert!(catalog.is_prototype(mother_id));
+        assert!(catalog.is_instance(mother_id));
+        assert!(!catalog.is_prototype(daughter_id));
+        assert!(catalog.is_instance(daughter_id));
+
+        catalog.delete(daughter_id);
+        assert!(!catalog.is_prototype(mother_id));
+        assert!(!catalog.is_instance(daughter_id));
+    }
+
+    #[test]
+    fn test_branch() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let other_id = catalog.create(Person::default());
+
+        let branch = catalog.branch();
+        branch
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 1)
+            .unwrap();
+        let branch_instance_id = branch.create_from_prototype(proto_id);
+        branch.delete(other_id);
+
+        assert_eq!(1, branch.get(proto_id).age);
+        assert_eq!(1, branch.get(instance_id).age);
+        assert_eq!(1, branch.get(branch_instance_id).age);
+        assert!(!branch.contains(other_id));
+
+        assert_eq!(0, catalog.get(proto_id).age);
+        assert_eq!(0, catalog.get(instance_id).age);
+        assert!(catalog.contains(other_id));
+
+        let parent_id = catalog.create(Person {
+            age: 50,
+            ..Default::default()
+        });
+        assert_eq!(branch_instance_id, parent_id);
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 2)
+            .unwrap();
+        assert_eq!(2, catalog.get(instance_id).age);
+        assert_eq!(50, catalog.get(parent_id).age);
+        assert_eq!(1, branch.get(instance_id).age);
+    }
+
+    #[test]
+    fn test_branch_validator() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        catalog.set_validator(|person| {
+            if person.age < 0 {
+                return Err(String::from("age can't be negative"));
+            }
+            Ok(())
+        });
+        let rejected = Arc::new(Mutex::new(vec![]));
+        catalog.on_commit_rejected({
+            let rejected = rejected.clone();
+            move |id, _| rejected.lock().unwrap().push(id)
+        });
+
+        let branch = catalog.branch();
+        assert!(branch.edit(id, |person| person.age = -1).is_err());
+        assert_eq!(0, branch.get(id).age);
+        assert_eq!(vec![id], *rejected.lock().unwrap());
+        branch.edit(id, |person| person.age = 4).unwrap();
+        assert_eq!(4, branch.get(id).age);
+        assert_eq!(0, catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_get_does_not_wait_on_writers() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 30,
+            ..Default::default()
+        });
+
+        let mut records = catalog.lock_records();
+        records.commit(
+            id,
+            Person {
+                age: 31,
+                ..Default::default()
+            },
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let reader = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                tx.send(catalog.get(id).age).unwrap();
+            })
+        };
+        assert_eq!(Ok(31), rx.recv_timeout(Duration::from_secs(5)));
+        drop(records);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_outlives_commit() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 7,
+            ..Default::default()
+        });
+
+        let person = catalog.get(id);
+        let writer = {
+            let library = library.clone();
+            thread::spawn(move || {
+                let catalog = library.checkout::<Person>();
+                catalog
+                    .lock(id)
+                    .unwrap()
+                    .mutate(|person| person.age = 8)
+                    .unwrap();
+            })
+        };
+        writer.join().unwrap();
+        drop(catalog);
+
+        assert_eq!(7, person.age);
+        assert_eq!(8, library.checkout::<Person>().get(id).age);
+    }
+
+    #[test]
+    fn test_peek_skips_reads() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 7,
+            ..Default::default()
+        });
+
+        assert_eq!(7, catalog.peek(id).age);
+        assert_eq!(0, catalog.reads.lock().unwrap().len());
+
+        assert_eq!(7, catalog.get(id).age);
+        assert_eq!(0, catalog.reads.lock().unwrap().len());
+
+        assert_eq!(7, catalog.lock(id).unwrap().value.age);
+        assert_eq!(1, catalog.reads.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_checkout_readonly() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start = catalog.watermark();
+        let id = catalog.create(Person {
+            age: 7,
+            ..Default::default()
+        });
+
+        let read_catalog = library.checkout_readonly::<Person>();
+        assert_eq!(7, read_catalog.get(id).age);
+        assert_eq!(1, read_catalog.len());
+        assert_eq!(
+            1,
+            read_catalog
+                .changes(start, read_catalog.watermark())
+                .count()
+        );
+        catalog.edit(id, |person| person.age = 8).unwrap();
+        assert_eq!(8, read_catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_get_arc() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person {
+            age: 7,
+            ..Default::default()
+        });
+
+        let person = catalog.get_arc(id);
+        catalog.edit(id, |person| person.age = 8).unwrap();
+        catalog.delete(id);
+        assert_eq!(7, person.age);
+        assert_eq!(1, Arc::strong_count(&person));
+    }
+
+    #[test]
+    fn test_clear_reads() {
+        let library = Library::default();
+        library.register::<Person>();
+        let mut catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        for age in 1..=3 {
+            catalog.edit(id, |person| person.age = age).unwrap();
+        }
+        assert_eq!(3, catalog.reads.lock().unwrap().len());
+
+        catalog.clear_reads();
+        assert_eq!(0, catalog.reads.lock().unwrap().len());
+        assert_eq!(3, catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_lock_all() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let thread = {
+            let guard = catalog.lock_all();
+            let thread = thread::spawn({
+                let library_copy = library.clone();
+                move || {
+                    let catalog = library_copy.checkout::<Person>();
+                    catalog
+                        .lock(id)
+                        .unwrap()
+                        .mutate(|person| person.age += 1)
+                        .unwrap();
+                    done_tx.send(()).unwrap();
+                }
+            });
+
+            thread::sleep(Duration::from_millis(20));
+            assert!(done_rx.try_recv().is_err());
+            guard
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.age = 10)
+                .unwrap();
+            assert_eq!(10, guard.get(id).age);
+            thread
+        };
+
+        done_rx.recv().unwrap();
+        thread.join().unwrap();
+        assert_eq!(11, catalog.get(id).age);
+    }
+
+    #[test]
+    fn test_lock_ownership() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+
+        assert!(!catalog.is_locked(id));
+        assert!(!catalog.is_locked_by_current_thread(id));
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let thread = thread::spawn({
+            let library_copy = library.clone();
+            move || {
+                let catalog = library_copy.checkout::<Person>();
+                let _person = catalog.lock(id).unwrap();
+                locked_tx
+                    .send((
+                        catalog.is_locked(id),
+                        catalog.is_locked_by_current_thread(id),
+                    ))
+                    .unwrap();
+                release_rx.recv().unwrap();
+            }
+        });
+
+        assert_eq!((true, true), locked_rx.recv().unwrap());
+        assert!(catalog.is_locked(id));
+        assert!(!catalog.is_locked_by_current_thread(id));
+
+        release_tx.send(()).unwrap();
+        thread.join().unwrap();
+        assert!(!catalog.is_locked(id));
+    }
+
+    #[test]
+    fn test_lock_ownership_unknown_ids() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let removed_id = catalog.create(Person::default());
+        let last_id = catalog.create(Person::default());
+        let (_, moved_to) = catalog.delete_swap(removed_id).unwrap();
+
+        let _locked = catalog.lock(moved_to).unwrap();
+        for id in [RecordId::INVALID, removed_id, last_id] {
+            assert!(!catalog.is_locked(id));
+            assert!(!catalog.is_locked_by_current_thread(id));
+            catalog.unlock(id);
+        }
+        assert!(catalog.is_locked_by_current_thread(moved_to));
+    }
+}
+
+// Run with `--cfg loom`, see `crate::sync`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use crate::{Library, Record, ReparentError};
+    use loom::thread;
+
+    #[derive(Clone, Debug, Default)]
+    struct Counter {
+        count: i32,
+    }
+
+    impl Record for Counter {
+        fn type_name() -> &'static str {
+            "Counter"
+        }
+
+        fn proto_update(&self, _old_prototype: &Counter, new_prototype: &Counter) -> Counter {
+            new_prototype.clone()
+        }
+    }
+
+    #[test]
+    fn loom_concurrent_commits() {
+        loom::model(|| {
+            let library = Library::default();
+            library.register::<Counter>();
+            let id = library.checkout::<Counter>().create(Counter::default());
+
+            let threads = (0..2)
+                .map(|_| {
+                    let library = library.clone();
+                    thread::spawn(move || {
+                        library
+                            .checkout::<Counter>()
+                            .edit(id, |counter| counter.count += 1)
+                            .unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let catalog = library.checkout::<Counter>();
+            assert_eq!(2, catalog.get(id).count);
+            assert!(!catalog.is_locked(id));
+        });
+    }
+
+    #[test]
+    fn loom_reparent_during_delete() {
+        loom::model(|| {
+            let library = Library::default();
+            library.register::<Counter>();
+            let catalog = library.checkout::<Counter>();
+            let old_proto_id = catalog.create(Counter::default());
+            let new_proto_id = catalog.create(Counter::default());
+            let instance_id = catalog.create_from_prototype(old_proto_id);
+
+            let delete = {
+                let library = library.clone();
+                thread::spawn(move || library.checkout::<Counter>().delete(new_proto_id))
+            };
+            let result = catalog.reparent_prototype(instance_id, new_proto_id);
+            delete.join().unwrap();
+
+            assert!(matches!(result, Ok(()) | Err(ReparentError::Missing(_))));
+            for id in [old_proto_id, instance_id] {
+                assert!(!catalog.is_locked(id));
+            }
+        });
+    }
+
+    #[test]
+    fn loom_detach_during_reparent() {
+        loom::model(|| {
+            let library = Library::default();
+            library.register::<Counter>();
+            let catalog = library.checkout::<Counter>();
+            let old_proto_id = catalog.create(Counter::default());
+            let new_proto_id = catalog.create(Counter::default());
+            let instance_id = catalog.create_from_prototype(old_proto_id);
+
+            let reparent = {
+                let library = library.clone();
+                thread::spawn(move || {
+                    library
+                        .checkout::<Counter>()
+                        .reparent_prototype(instance_id, new_proto_id)
+                        .unwrap();
+                })
+            };
+            catalog.detach_prototype(instance_id);
+            reparent.join().unwrap();
+
+            let is_listed = |proto_id| {
+                catalog
+                    .get_internal(proto_id, false)
+                    .prototype_instances
+                    .lock()
+                    .unwrap()
+                    .contains(&instance_id)
+            };
+            assert!(!is_listed(old_proto_id));
+            assert_eq!(
+                catalog.prototype_of(instance_id) == Some(new_proto_id),
+                is_listed(new_proto_id)
+            );
+            assert!(!catalog.is_locked(instance_id));
         });
     }
 }
@@ -1,9 +1,33 @@
 use crate::catalog::{Catalog, ChangeRecord};
-use crate::record::{Record, RecordId};
-use std::{iter::Iterator, marker::PhantomData};
+use crate::record::{Record, RecordId, RecordWrapper};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    iter::{DoubleEndedIterator, Iterator, Rev},
+    marker::PhantomData,
+    sync::Arc,
+    time::Duration,
+};
 
-#[derive(Copy, Clone)]
-pub struct Watermark(usize);
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Watermark(pub(crate) usize);
+
+impl Watermark {
+    // For persisting a watermark and resuming from it later with `from_u64`.
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Watermark(value as usize)
+    }
+
+    // Number of changes between the two watermarks, in either order.
+    pub fn distance(&self, other: &Watermark) -> usize {
+        self.0.abs_diff(other.0)
+    }
+}
 
 pub struct Change<'a, R>
 where
@@ -17,21 +41,229 @@ impl<'a, R> Change<'a, R>
 where
     R: Record,
 {
-    pub fn record_id(&self) -> RecordId {
+    pub fn record_id(&self) -> RecordId<R> {
         self.inner.record_id
     }
 
+    pub fn cause(&self) -> Option<RecordId<R>> {
+        self.inner.cause
+    }
+
     pub fn old_record(&self) -> Option<&R> {
         self.inner.old_record.as_ref().map(|r| &r.inner)
     }
 
-    pub fn new_record(&self) -> &R {
-        &self.inner.new_record.inner
+    pub fn new_record(&self) -> Option<&R> {
+        self.inner.new_record.as_ref().map(|r| &r.inner)
     }
 
     pub fn lsn(&self) -> u64 {
         self.inner.lsn
     }
+
+    // Shared by every change written by the same `Catalog::transaction`.
+    pub fn transaction(&self) -> Option<u64> {
+        self.inner.transaction
+    }
+
+    // Uses `Record::changed_fields`, so it's empty for creates, deletes and records that
+    // don't implement it.
+    pub fn changed_fields(&self) -> Vec<&'static str> {
+        match (self.old_record(), self.new_record()) {
+            (Some(old_record), Some(new_record)) => new_record.changed_fields(old_record),
+            _ => vec![],
+        }
+    }
+
+    pub fn to_snapshot(&self) -> ChangeSnapshot<R> {
+        ChangeSnapshot {
+            record_id: self.record_id(),
+            cause: self.cause(),
+            lsn: self.lsn(),
+            old_record: self.old_record().cloned(),
+            new_record: self.new_record().cloned(),
+        }
+    }
+}
+
+// Lets `AnyChange` hand its records back as their own type, and format them without it.
+trait ErasedRecord: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn fmt_record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl<R> ErasedRecord for RecordWrapper<R>
+where
+    R: Record,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn fmt_record(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+struct ErasedDebug<'a>(&'a dyn ErasedRecord);
+
+impl<'a> Debug for ErasedDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_record(f)
+    }
+}
+
+// A change from a catalog of any type, see `Library::for_each_change_since`. The records
+// can be read back with the catalog's record type, and `Debug` shows them whatever it is.
+#[derive(Clone)]
+pub struct AnyChange {
+    type_name: &'static str,
+    record_id: RecordId,
+    cause: Option<RecordId>,
+    lsn: u64,
+    old_record: Option<Arc<dyn ErasedRecord>>,
+    new_record: Option<Arc<dyn ErasedRecord>>,
+}
+
+impl AnyChange {
+    pub(crate) fn from_change_record<R>(change_record: &ChangeRecord<R>) -> Self
+    where
+        R: Record,
+    {
+        let erase = |record_wrapper: &Arc<RecordWrapper<R>>| -> Arc<dyn ErasedRecord> {
+            record_wrapper.clone()
+        };
+        AnyChange {
+            type_name: R::type_name(),
+            record_id: change_record.record_id.untyped(),
+            cause: change_record.cause.map(RecordId::untyped),
+            lsn: change_record.lsn,
+            old_record: change_record.old_record.as_ref().map(erase),
+            new_record: change_record.new_record.as_ref().map(erase),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn record_id(&self) -> RecordId {
+        self.record_id
+    }
+
+    pub fn cause(&self) -> Option<RecordId> {
+        self.cause
+    }
+
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    // `None` when there's no old record or it isn't an `R`.
+    pub fn old_record<R>(&self) -> Option<&R>
+    where
+        R: Record,
+    {
+        let record_wrapper = self
+            .old_record
+            .as_ref()?
+            .as_any()
+            .downcast_ref::<RecordWrapper<R>>()?;
+        Some(&record_wrapper.inner)
+    }
+
+    pub fn new_record<R>(&self) -> Option<&R>
+    where
+        R: Record,
+    {
+        let record_wrapper = self
+            .new_record
+            .as_ref()?
+            .as_any()
+            .downcast_ref::<RecordWrapper<R>>()?;
+        Some(&record_wrapper.inner)
+    }
+}
+
+impl Debug for AnyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnyChange")
+            .field("type_name", &self.type_name)
+            .field("record_id", &self.record_id)
+            .field("lsn", &self.lsn)
+            .field("old_record", &self.old_record.as_deref().map(ErasedDebug))
+            .field("new_record", &self.new_record.as_deref().map(ErasedDebug))
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeSnapshot<R> {
+    pub record_id: RecordId<R>,
+    pub cause: Option<RecordId<R>>,
+    pub lsn: u64,
+    pub old_record: Option<R>,
+    pub new_record: Option<R>,
+}
+
+// LSNs come from a sequencer shared by every catalog, so their values depend on how other
+// threads interleaved. This rewrites them to 0, 1, 2... keeping their relative order, which is
+// stable across runs of the same edits.
+pub fn normalize_lsns<R>(changes: &mut [ChangeSnapshot<R>]) {
+    let mut lsns = changes.iter().map(|change| change.lsn).collect::<Vec<_>>();
+    lsns.sort_unstable();
+    lsns.dedup();
+    let canonical_lsns = lsns
+        .into_iter()
+        .enumerate()
+        .map(|(index, lsn)| (lsn, index as u64))
+        .collect::<HashMap<_, _>>();
+    for change in changes {
+        change.lsn = canonical_lsns[&change.lsn];
+    }
+}
+
+// How much of a catalog's change log is kept, see `Catalog::set_history_policy`. Ages are
+// measured from when a change was written to this catalog.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HistoryPolicy {
+    #[default]
+    Unlimited,
+    MaxEntries(usize),
+    MaxAge(Duration),
+}
+
+// What a window of changes did to one record overall.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetChange<R> {
+    Created(RecordId<R>, R),
+    Updated(RecordId<R>, R, R),
+    Deleted(RecordId<R>),
+}
+
+// `Catalog::net_changes` grouped by what happened to each record, each group in the order
+// the records were first touched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff<R> {
+    pub created: Vec<(RecordId<R>, R)>,
+    pub modified: Vec<(RecordId<R>, R, R)>,
+    pub deleted: Vec<RecordId<R>>,
+}
+
+impl<R> Diff<R> {
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+impl<R> Default for Diff<R> {
+    fn default() -> Self {
+        Diff {
+            created: vec![],
+            modified: vec![],
+            deleted: vec![],
+        }
+    }
 }
 
 pub struct CatalogIterator<'a, R>
@@ -41,6 +273,36 @@ where
     catalog: &'a Catalog<R>,
     cur_watermark: Watermark,
     end_watermark: Watermark,
+    stale: bool,
+}
+
+impl<'a, R> CatalogIterator<'a, R>
+where
+    R: Record,
+{
+    // Set once the iterator ran into a part of the window that's no longer in the change log,
+    // e.g. because it was compacted mid-iteration. A stale iterator yields nothing more.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    fn change_at(&mut self, watermark: Watermark) -> Option<Change<'a, R>> {
+        let state = self.catalog.state.inner.lock().unwrap();
+        let change_record = watermark
+            .0
+            .checked_sub(state.change_log_start)
+            .and_then(|index| state.change_log.get(index));
+        match change_record {
+            Some(change_record) => Some(Change {
+                phantom: PhantomData,
+                inner: change_record.clone(),
+            }),
+            None => {
+                self.stale = true;
+                None
+            }
+        }
+    }
 }
 
 impl<'a, R> Iterator for CatalogIterator<'a, R>
@@ -50,17 +312,28 @@ where
     type Item = Change<'a, R>;
 
     fn next(&mut self) -> Option<Change<'a, R>> {
-        if self.end_watermark.0 <= self.cur_watermark.0 {
+        if self.stale || self.end_watermark.0 <= self.cur_watermark.0 {
             return None;
         }
 
-        let state = self.catalog.state.inner.lock().unwrap();
-        let change_record = state.change_log[self.cur_watermark.0].clone();
+        let change = self.change_at(self.cur_watermark)?;
         self.cur_watermark.0 += 1;
-        Some(Change {
-            phantom: PhantomData::default(),
-            inner: change_record,
-        })
+        Some(change)
+    }
+}
+
+impl<'a, R> DoubleEndedIterator for CatalogIterator<'a, R>
+where
+    R: Record,
+{
+    fn next_back(&mut self) -> Option<Change<'a, R>> {
+        if self.stale || self.end_watermark.0 <= self.cur_watermark.0 {
+            return None;
+        }
+
+        let change = self.change_at(Watermark(self.end_watermark.0 - 1))?;
+        self.end_watermark.0 -= 1;
+        Some(change)
     }
 }
 
@@ -68,22 +341,203 @@ impl<R> Catalog<R>
 where
     R: Record,
 {
-    pub fn changes(&self, start_point: Watermark, end_point: Watermark) -> CatalogIterator<R> {
+    pub fn changes(&self, start_point: Watermark, end_point: Watermark) -> CatalogIterator<'_, R> {
         CatalogIterator {
             catalog: self,
             cur_watermark: start_point,
             end_watermark: end_point,
+            stale: false,
         }
     }
 
+    pub fn changes_rev(
+        &self,
+        start_point: Watermark,
+        end_point: Watermark,
+    ) -> Rev<CatalogIterator<'_, R>> {
+        self.changes(start_point, end_point).rev()
+    }
+
+    pub fn changes_touching<'a>(
+        &'a self,
+        ids: &'a HashSet<RecordId<R>>,
+        start_point: Watermark,
+        end_point: Watermark,
+    ) -> impl Iterator<Item = Change<'a, R>> + 'a {
+        self.changes(start_point, end_point)
+            .filter(move |change| ids.contains(&change.record_id()))
+    }
+
+    pub fn changes_for(
+        &self,
+        id: RecordId<R>,
+        start_point: Watermark,
+        end_point: Watermark,
+    ) -> impl Iterator<Item = Change<'_, R>> {
+        self.changes(start_point, end_point)
+            .filter(move |change| change.record_id() == id)
+    }
+
+    pub fn snapshot_changes(
+        &self,
+        start_point: Watermark,
+        end_point: Watermark,
+    ) -> Vec<ChangeSnapshot<R>> {
+        self.changes(start_point, end_point)
+            .map(|change| change.to_snapshot())
+            .collect()
+    }
+
+    // One entry per record the window touched, in the order they were first touched, going
+    // from the record before the window to the record after it. Records created and deleted
+    // within the window are left out.
+    pub fn net_changes(&self, start_point: Watermark, end_point: Watermark) -> Vec<NetChange<R>> {
+        let mut record_ids = vec![];
+        let mut net_records = HashMap::<RecordId<R>, (Option<R>, Option<R>)>::new();
+        for change in self.changes(start_point, end_point) {
+            let new_record = change.new_record().cloned();
+            net_records
+                .entry(change.record_id())
+                .and_modify(|(_, after)| *after = new_record.clone())
+                .or_insert_with(|| {
+                    record_ids.push(change.record_id());
+                    (change.old_record().cloned(), new_record)
+                });
+        }
+
+        record_ids
+            .into_iter()
+            .filter_map(|id| match net_records.remove(&id)? {
+                (None, Some(after)) => Some(NetChange::Created(id, after)),
+                (Some(before), Some(after)) => Some(NetChange::Updated(id, before, after)),
+                (Some(_), None) => Some(NetChange::Deleted(id)),
+                (None, None) => None,
+            })
+            .collect()
+    }
+
+    pub fn diff(&self, from: Watermark, to: Watermark) -> Diff<R> {
+        let mut diff = Diff::default();
+        for net_change in self.net_changes(from, to) {
+            match net_change {
+                NetChange::Created(id, after) => diff.created.push((id, after)),
+                NetChange::Updated(id, before, after) => diff.modified.push((id, before, after)),
+                NetChange::Deleted(id) => diff.deleted.push(id),
+            }
+        }
+        diff
+    }
+
     pub fn watermark(&self) -> Watermark {
-        Watermark(self.state.inner.lock().unwrap().change_log.len())
+        let state = self.state.inner.lock().unwrap();
+        Watermark(state.change_log_start + state.change_log.len())
+    }
+
+    // The earliest change still in the log. Windows starting before it have been compacted or
+    // trimmed, and iterating them goes stale straight away.
+    pub fn oldest_watermark(&self) -> Watermark {
+        Watermark(self.state.inner.lock().unwrap().change_log_start)
+    }
+
+    // Drops every change before `up_to`. Watermarks stay absolute, so ones taken before
+    // compacting still point at the same changes, or past the start of the log.
+    pub fn compact_change_log(&self, up_to: Watermark) -> usize {
+        self.state.inner.lock().unwrap().compact_change_log(up_to)
+    }
+
+    // Keeps the change log at `max` changes at most, dropping the oldest.
+    pub fn set_change_log_limit(&self, max: usize) {
+        self.set_history_policy(HistoryPolicy::MaxEntries(max));
+    }
+
+    // Trims the change log on every change from now on, starting with the log as it is.
+    // Iterators that reach a trimmed change go stale, like they do after `compact_change_log`.
+    pub fn set_history_policy(&self, policy: HistoryPolicy) {
+        let mut state = self.state.inner.lock().unwrap();
+        state.history_policy = policy;
+        state.apply_history_policy();
+    }
+
+    pub fn distance(&self, from: Watermark) -> usize {
+        self.watermark().distance(&from)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{tests::Person, Library};
+    use super::{HistoryPolicy, Watermark};
+    use crate::{
+        normalize_lsns, proto_update_field,
+        tests::{Dog, Person},
+        Library, NetChange, Record,
+    };
+    use std::{collections::HashSet, thread, time::Duration};
+
+    #[test]
+    fn test_changes_rev() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person::default());
+        for age in 1..4 {
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.age = age)
+                .unwrap();
+        }
+        let end_watermark = catalog.watermark();
+
+        let mut forward = catalog
+            .changes(start_watermark, end_watermark)
+            .map(|change| change.lsn())
+            .collect::<Vec<_>>();
+        let reversed = catalog
+            .changes_rev(start_watermark, end_watermark)
+            .map(|change| change.lsn())
+            .collect::<Vec<_>>();
+        forward.reverse();
+        assert_eq!(4, reversed.len());
+        assert_eq!(forward, reversed);
+
+        let mut changes = catalog.changes(start_watermark, end_watermark);
+        assert_eq!(
+            Some(0),
+            changes
+                .next()
+                .map(|change| change.new_record().unwrap().age)
+        );
+        assert_eq!(
+            Some(3),
+            changes
+                .next_back()
+                .map(|change| change.new_record().unwrap().age)
+        );
+        assert_eq!(2, changes.count());
+    }
+
+    #[test]
+    fn test_distance() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        let start_watermark = catalog.watermark();
+
+        for age in 0..5 {
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.age = age)
+                .unwrap();
+        }
+
+        assert_eq!(5, catalog.distance(start_watermark));
+        assert_eq!(5, start_watermark.distance(&catalog.watermark()));
+        assert_eq!(5, catalog.watermark().distance(&start_watermark));
+        assert_eq!(0, catalog.distance(catalog.watermark()));
+    }
 
     #[test]
     fn test_change_detection() {
@@ -99,17 +553,17 @@ mod tests {
         });
 
         {
-            let person = catalog.lock(id);
+            let person = catalog.lock(id).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("Name1");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
         }
 
         {
-            let person = catalog.lock(id);
+            let person = catalog.lock(id).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("Name2");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
         }
 
         let end_watermark = catalog.watermark();
@@ -118,10 +572,10 @@ mod tests {
             .changes(start_watermark, end_watermark)
             .collect::<Vec<_>>();
         assert_eq!(3, changes.len());
-        assert_eq!(true, changes[0].inner.old_record.is_none());
+        assert!(changes[0].inner.old_record.is_none());
         assert_eq!(
             String::from("Name0"),
-            changes[0].inner.new_record.inner.name
+            changes[0].inner.new_record.as_ref().unwrap().inner.name
         );
         assert_eq!(
             String::from("Name0"),
@@ -129,7 +583,7 @@ mod tests {
         );
         assert_eq!(
             String::from("Name1"),
-            changes[1].inner.new_record.inner.name
+            changes[1].inner.new_record.as_ref().unwrap().inner.name
         );
         assert_eq!(
             String::from("Name1"),
@@ -137,7 +591,457 @@ mod tests {
         );
         assert_eq!(
             String::from("Name2"),
-            changes[2].inner.new_record.inner.name
+            changes[2].inner.new_record.as_ref().unwrap().inner.name
         );
     }
+
+    #[test]
+    fn test_change_detection_rev() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person {
+            name: String::from("Name0"),
+            ..Default::default()
+        });
+        for name in ["Name1", "Name2"] {
+            catalog
+                .edit(id, |person| person.name = String::from(name))
+                .unwrap();
+        }
+        let end_watermark = catalog.watermark();
+
+        let changes = catalog
+            .changes_rev(start_watermark, end_watermark)
+            .map(|change| {
+                (
+                    change.old_record().map(|person| person.name.clone()),
+                    change.new_record().map(|person| person.name.clone()),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Some(String::from("Name1")), Some(String::from("Name2"))),
+                (Some(String::from("Name0")), Some(String::from("Name1"))),
+                (None, Some(String::from("Name0"))),
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn test_change_log_limit() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person::default());
+        let middle_watermark = catalog.watermark();
+        catalog.set_change_log_limit(3);
+        for age in 1..6 {
+            catalog.edit(id, |person| person.age = age).unwrap();
+        }
+        let end_watermark = catalog.watermark();
+
+        assert_eq!(3, catalog.oldest_watermark().distance(&end_watermark));
+        assert_eq!(6, start_watermark.distance(&end_watermark));
+        let mut changes = catalog.changes(middle_watermark, end_watermark);
+        assert!(changes.next().is_none());
+        assert!(changes.is_stale());
+        let ages = catalog
+            .changes(catalog.oldest_watermark(), end_watermark)
+            .map(|change| change.new_record().unwrap().age)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![3, 4, 5], ages);
+    }
+
+    #[test]
+    fn test_changes_for() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person::default());
+        let other_id = catalog.create(Person::default());
+        for age in 1..3 {
+            catalog.edit(id, |person| person.age = age).unwrap();
+            catalog
+                .edit(other_id, |person| person.age = age * 10)
+                .unwrap();
+        }
+
+        let ages = catalog
+            .changes_for(other_id, start_watermark, catalog.watermark())
+            .map(|change| {
+                assert_eq!(other_id, change.record_id());
+                change.new_record().unwrap().age
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 10, 20], ages);
+    }
+
+    #[test]
+    fn test_diff() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let kept_id = catalog.create(Person::default());
+        let deleted_id = catalog.create(Person::default());
+        let start_watermark = catalog.watermark();
+        assert!(catalog.diff(start_watermark, start_watermark).is_empty());
+
+        for age in 1..4 {
+            catalog.edit(kept_id, |person| person.age = age).unwrap();
+        }
+        catalog.delete(deleted_id);
+        let created_id = catalog.create(Person {
+            age: 9,
+            ..Default::default()
+        });
+
+        let diff = catalog.diff(start_watermark, catalog.watermark());
+        assert_eq!(
+            vec![(kept_id, 0, 3)],
+            diff.modified
+                .iter()
+                .map(|(id, before, after)| (*id, before.age, after.age))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec![deleted_id], diff.deleted);
+        assert_eq!(
+            vec![(created_id, 9)],
+            diff.created
+                .iter()
+                .map(|(id, after)| (*id, after.age))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_watermark_ordering() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        catalog.create(Person::default());
+        catalog.create(Person::default());
+        let end_watermark = catalog.watermark();
+
+        assert!(start_watermark < end_watermark);
+        assert_eq!(end_watermark, start_watermark.max(end_watermark));
+        assert_eq!(2, end_watermark.distance(&start_watermark));
+        let resumed = Watermark::from_u64(end_watermark.as_u64());
+        assert_eq!(end_watermark, resumed);
+        assert_eq!(0, catalog.changes(resumed, catalog.watermark()).count());
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person::default());
+        catalog
+            .lock(id)
+            .unwrap()
+            .mutate(|person| person.fav_food = String::from("Plums"))
+            .unwrap();
+
+        let changed_fields = catalog
+            .changes(start_watermark, catalog.watermark())
+            .map(|change| change.changed_fields())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![vec![], vec!["fav_food"]], changed_fields);
+    }
+
+    #[test]
+    fn test_compact_mid_iteration() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let id = catalog.create(Person::default());
+        for age in 1..5 {
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.age = age)
+                .unwrap();
+        }
+        let end_watermark = catalog.watermark();
+
+        let mut changes = catalog.changes(start_watermark, end_watermark);
+        assert_eq!(
+            Some(0),
+            changes
+                .next()
+                .map(|change| change.new_record().unwrap().age)
+        );
+        assert_eq!(3, catalog.compact_change_log(Watermark(3)));
+        assert!(changes.next().is_none());
+        assert!(changes.is_stale());
+        assert!(changes.next().is_none());
+
+        let mut changes = catalog.changes(Watermark(3), end_watermark);
+        assert_eq!(
+            Some(3),
+            changes
+                .next()
+                .map(|change| change.new_record().unwrap().age)
+        );
+        assert_eq!(
+            Some(4),
+            changes
+                .next()
+                .map(|change| change.new_record().unwrap().age)
+        );
+        assert!(changes.next().is_none());
+        assert!(!changes.is_stale());
+        assert_eq!(end_watermark.0, catalog.watermark().0);
+    }
+
+    #[test]
+    fn test_history_policy() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        catalog.set_history_policy(HistoryPolicy::MaxEntries(5));
+        let id = catalog.create(Person::default());
+        let mut changes = catalog.changes(start_watermark, Watermark(10));
+        assert!(changes.next().is_some());
+        for age in 1..10 {
+            catalog.edit(id, |person| person.age = age).unwrap();
+        }
+        assert!(changes.next().is_none());
+        assert!(changes.is_stale());
+
+        let end_watermark = catalog.watermark();
+        assert_eq!(10, end_watermark.distance(&start_watermark));
+        let ages = catalog
+            .changes(start_watermark, end_watermark)
+            .map(|change| change.new_record().unwrap().age)
+            .collect::<Vec<_>>();
+        assert!(ages.is_empty());
+        let ages = catalog
+            .changes(Watermark(5), end_watermark)
+            .map(|change| change.new_record().unwrap().age)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![5, 6, 7, 8, 9], ages);
+
+        catalog.set_history_policy(HistoryPolicy::MaxAge(Duration::ZERO));
+        assert!(catalog
+            .changes(Watermark(5), end_watermark)
+            .next()
+            .is_none());
+        catalog.set_history_policy(HistoryPolicy::Unlimited);
+        catalog.edit(id, |person| person.age = 10).unwrap();
+        catalog.edit(id, |person| person.age = 11).unwrap();
+        assert_eq!(
+            2,
+            catalog.changes(end_watermark, catalog.watermark()).count()
+        );
+    }
+
+    #[test]
+    fn test_changes_touching() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let start_watermark = catalog.watermark();
+        let parent_id = catalog.create(Person::default());
+        let child_id = catalog.create(Person::default());
+        let other_id = catalog.create(Person::default());
+        for &id in &[parent_id, child_id, other_id, parent_id] {
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.age += 1)
+                .unwrap();
+        }
+
+        let ids = vec![parent_id, child_id]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let touched = catalog
+            .changes_touching(&ids, start_watermark, catalog.watermark())
+            .map(|change| change.record_id())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![parent_id, child_id, parent_id, child_id, parent_id],
+            touched
+        );
+    }
+
+    #[test]
+    fn test_net_changes() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let updated_id = catalog.create(Person::default());
+        let deleted_id = catalog.create(Person::default());
+        let start_watermark = catalog.watermark();
+
+        catalog.edit(updated_id, |person| person.age = 1).unwrap();
+        let created_id = catalog.create(Person::default());
+        catalog.edit(created_id, |person| person.age = 5).unwrap();
+        let temporary_id = catalog.create(Person::default());
+        catalog.delete(temporary_id);
+        catalog.edit(updated_id, |person| person.age = 2).unwrap();
+        catalog.delete(deleted_id);
+
+        let age = |person: &Person| person.age;
+        let summary = catalog
+            .net_changes(start_watermark, catalog.watermark())
+            .into_iter()
+            .map(|net_change| match net_change {
+                NetChange::Created(id, after) => (id, None, Some(age(&after))),
+                NetChange::Updated(id, before, after) => {
+                    (id, Some(age(&before)), Some(age(&after)))
+                }
+                NetChange::Deleted(id) => (id, None, None),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (updated_id, Some(0), Some(2)),
+                (created_id, None, Some(5)),
+                (deleted_id, None, None),
+            ],
+            summary
+        );
+    }
+
+    #[test]
+    fn test_normalize_lsns() {
+        let run_edits = || {
+            let library = Library::default();
+            library.register::<Person>();
+            library.register::<Dog>();
+            let person_catalog = library.checkout::<Person>();
+            let start_watermark = person_catalog.watermark();
+
+            let dog_thread = {
+                let library = library.clone();
+                thread::spawn(move || {
+                    let dog_catalog = library.checkout::<Dog>();
+                    for dog_years in 0..50 {
+                        dog_catalog.create(Dog { dog_years });
+                    }
+                })
+            };
+            let proto_id = person_catalog.create(Person::default());
+            for age in 0..50 {
+                let id = person_catalog.create_from_prototype(proto_id);
+                person_catalog
+                    .lock(id)
+                    .unwrap()
+                    .mutate(|person| person.age = age)
+                    .unwrap();
+            }
+            dog_thread.join().unwrap();
+
+            let mut snapshot =
+                person_catalog.snapshot_changes(start_watermark, person_catalog.watermark());
+            normalize_lsns(&mut snapshot);
+            format!("{:?}", snapshot)
+        };
+
+        let snapshot = run_edits();
+        assert!(snapshot.contains("lsn: 100"));
+        assert_eq!(snapshot, run_edits());
+    }
+
+    #[test]
+    fn test_change_cause() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let grandmother_id = catalog.create(Person::default());
+        let mother_id = catalog.create_from_prototype(grandmother_id);
+        let daughter_id = catalog.create_from_prototype(mother_id);
+        let start_watermark = catalog.watermark();
+
+        catalog
+            .lock(grandmother_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Grandma"))
+            .unwrap();
+
+        let changes = catalog
+            .changes(start_watermark, catalog.watermark())
+            .map(|change| (change.record_id(), change.cause()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (grandmother_id, None),
+                (mother_id, Some(grandmother_id)),
+                (daughter_id, Some(mother_id)),
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn test_snapshot_for_history() {
+        let library = Library::default();
+        library.register::<Mesh>();
+        let catalog = library.checkout::<Mesh>();
+        let start_watermark = catalog.watermark();
+
+        let id = catalog.create(Mesh {
+            vertex_count: 3,
+            cache: vec![0; 3],
+        });
+        catalog
+            .lock(id)
+            .unwrap()
+            .mutate(|mesh| {
+                mesh.vertex_count = 4;
+                mesh.cache = vec![0; 4];
+            })
+            .unwrap();
+
+        assert_eq!(4, catalog.get(id).cache.len());
+        let changes = catalog
+            .changes(start_watermark, catalog.watermark())
+            .collect::<Vec<_>>();
+        assert_eq!(2, changes.len());
+        assert_eq!(3, changes[1].old_record().unwrap().vertex_count);
+        assert!(changes[1].old_record().unwrap().cache.is_empty());
+        assert_eq!(4, changes[1].new_record().unwrap().vertex_count);
+        assert!(changes[1].new_record().unwrap().cache.is_empty());
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Mesh {
+        vertex_count: usize,
+        cache: Vec<u8>,
+    }
+    impl Record for Mesh {
+        fn type_name() -> &'static str {
+            "Mesh"
+        }
+
+        fn proto_update(&self, old: &Mesh, new: &Mesh) -> Mesh {
+            Mesh {
+                vertex_count: *proto_update_field(
+                    &self.vertex_count,
+                    &old.vertex_count,
+                    &new.vertex_count,
+                ),
+                cache: Default::default(),
+            }
+        }
+
+        fn snapshot_for_history(&self) -> Mesh {
+            Mesh {
+                vertex_count: self.vertex_count,
+                cache: Default::default(),
+            }
+        }
+    }
 }
@@ -0,0 +1,804 @@
+use crate::{
+    catalog::{Catalog, CatalogState, ChangeRecord},
+    library::{Library, Sequencer},
+    record::{Record, RecordId, RecordWrapper},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Debug, Display},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+// Bumped whenever the layout of a saved `LibrarySnapshot` changes. `Library::load` migrates the
+// older versions it knows and rejects anything else.
+// 1: the first version.
+// 2: adds `sparse`. Version 1 snapshots load as dense ones.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+// A change as handed to the write-ahead log sink. `line` is in the format
+// `Catalog::import_change_log_from` reads, one change per line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerializedChange {
+    pub record_id: RecordId,
+    pub lsn: u64,
+    pub line: String,
+}
+
+type WalSink = Arc<dyn Fn(&'static str, &SerializedChange) + Send + Sync>;
+
+#[derive(Default, Clone)]
+pub(crate) struct WriteAheadLog {
+    sink: Arc<Mutex<Option<WalSink>>>,
+}
+
+impl WriteAheadLog {
+    pub(crate) fn write<R>(&self, catalog_state: &CatalogState<R>, change_record: &ChangeRecord<R>)
+    where
+        R: Record,
+    {
+        let serialize_change = match catalog_state.serialize_change {
+            Some(serialize_change) => serialize_change,
+            None => return,
+        };
+        let sink = self.sink.lock().unwrap().clone();
+        if let Some(sink) = sink {
+            sink(
+                R::type_name(),
+                &SerializedChange {
+                    record_id: change_record.record_id.untyped(),
+                    lsn: change_record.lsn,
+                    line: serialize_change(change_record).to_string(),
+                },
+            );
+        }
+    }
+}
+
+impl Debug for WriteAheadLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteAheadLog")
+            .field("has_sink", &self.sink.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+pub(crate) type DeserializeChange<R> = fn(serde_json::Value) -> serde_json::Result<ChangeRecord<R>>;
+
+fn serialize_change_record<R>(change_record: &ChangeRecord<R>) -> serde_json::Value
+where
+    R: Record + Serialize,
+{
+    serde_json::to_value(SerializedChangeRecord::from_change_record(change_record))
+        .expect("record failed to serialize")
+}
+
+fn serialize_default_record<R>() -> serde_json::Value
+where
+    R: Record + Serialize,
+{
+    serde_json::to_value(R::default()).expect("record failed to serialize")
+}
+
+// The records a serialized change holds, when they serialize as maps. Other records are
+// always stored whole.
+fn serialized_records_mut(
+    change: &mut serde_json::Value,
+) -> impl Iterator<Item = &mut serde_json::Map<String, serde_json::Value>> {
+    change
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|change| change.iter_mut())
+        .filter(|(key, _)| *key == "old_record" || *key == "new_record")
+        .filter_map(|(_, record)| record.get_mut("record")?.as_object_mut())
+}
+
+fn remove_default_fields(change: &mut serde_json::Value, default_record: &serde_json::Value) {
+    for record in serialized_records_mut(change) {
+        record.retain(|field, value| default_record.get(field) != Some(value));
+    }
+}
+
+fn restore_default_fields(change: &mut serde_json::Value, default_record: &serde_json::Value) {
+    let default_fields = match default_record.as_object() {
+        Some(default_fields) => default_fields,
+        None => return,
+    };
+    for record in serialized_records_mut(change) {
+        for (field, default_value) in default_fields {
+            record
+                .entry(field.clone())
+                .or_insert_with(|| default_value.clone());
+        }
+    }
+}
+
+fn deserialize_change_record<R>(value: serde_json::Value) -> serde_json::Result<ChangeRecord<R>>
+where
+    R: Record + DeserializeOwned,
+{
+    serde_json::from_value::<SerializedChangeRecord<R>>(value)
+        .map(SerializedChangeRecord::into_change_record)
+}
+
+pub(crate) fn export_changes<R>(catalog_state: &CatalogState<R>) -> Vec<serde_json::Value>
+where
+    R: Record,
+{
+    let serialize_change = match catalog_state.serialize_change {
+        Some(serialize_change) => serialize_change,
+        None => return vec![],
+    };
+    let change_log = catalog_state.inner.lock().unwrap().change_log.clone();
+    change_log.iter().map(serialize_change).collect()
+}
+
+// Every change is parsed before any is replayed, so a malformed snapshot leaves the catalog as
+// it was.
+pub(crate) fn import_changes<R>(
+    catalog_state: &CatalogState<R>,
+    changes: Vec<serde_json::Value>,
+    sequencer: &Sequencer,
+) -> Result<(), LoadError>
+where
+    R: Record,
+{
+    let deserialize_change = match catalog_state.deserialize_change {
+        Some(deserialize_change) => deserialize_change,
+        None => return Err(LoadError::UnregisteredCatalog(R::type_name().to_string())),
+    };
+    let change_records = changes
+        .into_iter()
+        .map(deserialize_change)
+        .collect::<serde_json::Result<Vec<_>>>()?;
+    let mut state_inner = catalog_state.inner.lock().unwrap();
+    for change_record in change_records {
+        sequencer.advance_past(change_record.lsn);
+        state_inner.replay(change_record);
+    }
+    Ok(())
+}
+
+// A whole library as written by `Library::save`: every serializable catalog's change log,
+// keyed by type name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LibrarySnapshot {
+    #[serde(default)]
+    pub format_version: u32,
+    // Whether record fields holding their `Default` value were left out.
+    #[serde(default)]
+    pub sparse: bool,
+    pub catalogs: BTreeMap<String, Vec<serde_json::Value>>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    UnsupportedVersion(u32),
+    UnregisteredCatalog(String),
+    Malformed(serde_json::Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot format version {} isn't supported, expected {}",
+                version, SNAPSHOT_FORMAT_VERSION
+            ),
+            LoadError::UnregisteredCatalog(type_name) => write!(
+                f,
+                "catalog {} isn't registered with register_serializable",
+                type_name
+            ),
+            LoadError::Malformed(error) => write!(f, "malformed snapshot: {}", error),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Malformed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(error: serde_json::Error) -> Self {
+        LoadError::Malformed(error)
+    }
+}
+
+impl Library {
+    // Like `register`, but the catalog's changes also go to the write-ahead log sink and it's
+    // included in `save` and `load`.
+    pub fn register_serializable<R>(&self)
+    where
+        R: Record + Serialize + DeserializeOwned,
+    {
+        self.register_state::<R>(|catalog_state| {
+            catalog_state.serialize_change = Some(serialize_change_record::<R>);
+            catalog_state.deserialize_change = Some(deserialize_change_record::<R>);
+            catalog_state.serialize_default_record = Some(serialize_default_record::<R>);
+        });
+    }
+
+    // Only catalogs registered with `register_serializable` are part of the snapshot.
+    pub fn snapshot(&self) -> LibrarySnapshot {
+        self.snapshot_internal(false)
+    }
+
+    // Like `snapshot`, but record fields that hold their `Default` value are left out, which
+    // keeps saves of mostly default records small.
+    pub fn sparse_snapshot(&self) -> LibrarySnapshot {
+        self.snapshot_internal(true)
+    }
+
+    fn snapshot_internal(&self, sparse: bool) -> LibrarySnapshot {
+        let catalogs = self.catalogs.lock().unwrap().clone();
+        LibrarySnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            sparse,
+            catalogs: catalogs
+                .iter()
+                .filter(|(_, catalog_state)| catalog_state.is_serializable())
+                .map(|(type_name, catalog_state)| {
+                    let mut changes = catalog_state.export_changes();
+                    if sparse {
+                        let default_record = catalog_state.serialize_default_record();
+                        for change in &mut changes {
+                            remove_default_fields(change, &default_record);
+                        }
+                    }
+                    (type_name.clone(), changes)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn save<W>(&self, writer: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        serde_json::to_writer(writer, &self.snapshot())?;
+        Ok(())
+    }
+
+    pub fn save_sparse<W>(&self, writer: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        serde_json::to_writer(writer, &self.sparse_snapshot())?;
+        Ok(())
+    }
+
+    // Meant for a fresh library with the snapshot's catalogs already registered with
+    // `register_serializable`. The version is checked before anything else is parsed, so a
+    // snapshot from another version fails cleanly even if its layout changed.
+    pub fn load<Rd>(&self, reader: Rd) -> Result<(), LoadError>
+    where
+        Rd: Read,
+    {
+        let value = serde_json::from_reader::<_, serde_json::Value>(reader)?;
+        let format_version = value
+            .get("format_version")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0) as u32;
+        if !(1..=SNAPSHOT_FORMAT_VERSION).contains(&format_version) {
+            return Err(LoadError::UnsupportedVersion(format_version));
+        }
+        let snapshot = serde_json::from_value::<LibrarySnapshot>(value)?;
+
+        let catalogs = self.catalogs.lock().unwrap().clone();
+        let catalog_states = snapshot
+            .catalogs
+            .keys()
+            .map(|type_name| match catalogs.get(type_name) {
+                Some(catalog_state) if catalog_state.is_serializable() => Ok(catalog_state),
+                _ => Err(LoadError::UnregisteredCatalog(type_name.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (catalog_state, (_, mut changes)) in catalog_states.into_iter().zip(snapshot.catalogs) {
+            if snapshot.sparse {
+                let default_record = catalog_state.serialize_default_record();
+                for change in &mut changes {
+                    restore_default_fields(change, &default_record);
+                }
+            }
+            catalog_state.import_changes(changes, &self.sequencer)?;
+        }
+        Ok(())
+    }
+
+    // The sink runs synchronously while the catalog's state is held, so every change reaches it
+    // in log order before the commit returns. It must not write to the catalog it's given
+    // changes for. Only catalogs registered with `register_serializable` are logged.
+    pub fn set_wal_sink<F>(&self, f: F)
+    where
+        F: Fn(&'static str, &SerializedChange) + Send + Sync + 'static,
+    {
+        *self.write_ahead_log.sink.lock().unwrap() = Some(Arc::from(f));
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedRecord<R> {
+    prototype_id: Option<RecordId<R>>,
+    #[serde(default)]
+    override_mask: u64,
+    record: R,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedChangeRecord<R> {
+    record_id: RecordId<R>,
+    cause: Option<RecordId<R>>,
+    lsn: u64,
+    transaction: Option<u64>,
+    old_record: Option<SerializedRecord<R>>,
+    new_record: Option<SerializedRecord<R>>,
+}
+
+impl<R> SerializedChangeRecord<R>
+where
+    R: Record,
+{
+    fn from_change_record(change_record: &ChangeRecord<R>) -> Self {
+        let serialize_record = |record_wrapper: &Arc<RecordWrapper<R>>| SerializedRecord {
+            prototype_id: record_wrapper.prototype_id,
+            override_mask: record_wrapper.override_mask,
+            record: record_wrapper.inner.clone(),
+        };
+        SerializedChangeRecord {
+            record_id: change_record.record_id,
+            cause: change_record.cause,
+            lsn: change_record.lsn,
+            transaction: change_record.transaction,
+            old_record: change_record.old_record.as_ref().map(serialize_record),
+            new_record: change_record.new_record.as_ref().map(serialize_record),
+        }
+    }
+
+    fn into_change_record(self) -> ChangeRecord<R> {
+        let generation = self.record_id.1;
+        let deserialize_record = |serialized_record: SerializedRecord<R>| {
+            Arc::from(RecordWrapper {
+                prototype_id: serialized_record.prototype_id,
+                prototype_instances: Default::default(),
+                override_mask: serialized_record.override_mask,
+                generation,
+                inner: serialized_record.record,
+            })
+        };
+        ChangeRecord {
+            record_id: self.record_id,
+            cause: self.cause,
+            lsn: self.lsn,
+            transaction: self.transaction,
+            committed_at: Instant::now(),
+            old_record: self.old_record.map(deserialize_record),
+            new_record: self.new_record.map(deserialize_record),
+        }
+    }
+}
+
+impl<R> Catalog<R>
+where
+    R: Record + Serialize + DeserializeOwned,
+{
+    // Writes one JSON change per line, so the writer can be wrapped in whatever compression
+    // the caller likes.
+    pub fn export_change_log_to<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let change_log = self.state.inner.lock().unwrap().change_log.clone();
+        for change_record in &change_log {
+            serde_json::to_writer(
+                &mut writer,
+                &SerializedChangeRecord::from_change_record(change_record),
+            )?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+
+    // Replays an exported log onto this catalog in LSN order, whatever order the lines are
+    // in, keeping the original LSNs. Meant for a fresh catalog, and change listeners aren't
+    // notified. Nothing is replayed if any line fails to parse. Returns the number of changes
+    // imported.
+    pub fn import_change_log_from<Rd>(&self, reader: Rd) -> io::Result<usize>
+    where
+        Rd: Read,
+    {
+        let mut change_records = serde_json::Deserializer::from_reader(reader)
+            .into_iter::<SerializedChangeRecord<R>>()
+            .map(|serialized_change| Ok(serialized_change?.into_change_record()))
+            .collect::<io::Result<Vec<_>>>()?;
+        change_records.sort_by_key(|change_record| change_record.lsn);
+        let imported = change_records.len();
+        let mut state_inner = self.state.inner.lock().unwrap();
+        for change_record in change_records {
+            self.sequencer.advance_past(change_record.lsn);
+            state_inner.replay(change_record);
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tests::Person, Catalog, Library, LibrarySnapshot, LoadError, SerializedChange,
+        SNAPSHOT_FORMAT_VERSION,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn change_summary(catalog: &Catalog<Person>) -> Vec<String> {
+        catalog
+            .state
+            .inner
+            .lock()
+            .unwrap()
+            .change_log
+            .iter()
+            .map(|change_record| {
+                format!(
+                    "{:?} {:?} {} {:?} {:?}",
+                    change_record.record_id,
+                    change_record.cause,
+                    change_record.lsn,
+                    change_record.old_record.as_ref().map(|r| (
+                        r.prototype_id,
+                        &r.inner.name,
+                        r.inner.age
+                    )),
+                    change_record.new_record.as_ref().map(|r| (
+                        r.prototype_id,
+                        &r.inner.name,
+                        r.inner.age
+                    )),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_wal_sink() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let wal = Arc::new(Mutex::new(Vec::<(&'static str, SerializedChange)>::new()));
+        {
+            let wal = wal.clone();
+            library.set_wal_sink(move |type_name, change| {
+                wal.lock().unwrap().push((type_name, change.clone()))
+            });
+        }
+
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        catalog
+            .lock(id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Eva"))
+            .unwrap();
+
+        let wal = wal.lock().unwrap();
+        assert_eq!(2, wal.len());
+        assert_eq!(
+            ("Person", id.untyped(), 1),
+            (wal[1].0, wal[1].1.record_id, wal[1].1.lsn)
+        );
+
+        let replayed_library = Library::default();
+        replayed_library.register::<Person>();
+        let replayed_catalog = replayed_library.checkout::<Person>();
+        let lines = wal
+            .iter()
+            .map(|(_, change)| format!("{}\n", change.line))
+            .collect::<String>();
+        replayed_catalog
+            .import_change_log_from(lines.as_bytes())
+            .unwrap();
+        assert_eq!(String::from("Eva"), replayed_catalog.get(id).name);
+    }
+
+    #[test]
+    fn test_change_log_round_trip() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let other_id = catalog.create(Person::default());
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 30)
+            .unwrap();
+        catalog.delete(other_id);
+
+        let mut buffer = vec![];
+        catalog.export_change_log_to(&mut buffer).unwrap();
+
+        let imported_library = Library::default();
+        imported_library.register::<Person>();
+        let imported_catalog = imported_library.checkout::<Person>();
+        assert_eq!(
+            6,
+            imported_catalog
+                .import_change_log_from(buffer.as_slice())
+                .unwrap()
+        );
+
+        assert_eq!(change_summary(&catalog), change_summary(&imported_catalog));
+        assert_eq!(30, imported_catalog.get(instance_id).age);
+        assert!(!imported_catalog.contains(other_id));
+
+        imported_catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 31)
+            .unwrap();
+        assert_eq!(31, imported_catalog.get(instance_id).age);
+    }
+
+    #[test]
+    fn test_deleted_prototype_round_trip() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        catalog.delete(proto_id);
+        assert_eq!(None, catalog.prototype_of(instance_id));
+
+        let mut buffer = vec![];
+        catalog.export_change_log_to(&mut buffer).unwrap();
+
+        let imported_library = Library::default();
+        imported_library.register::<Person>();
+        let imported_catalog = imported_library.checkout::<Person>();
+        imported_catalog
+            .import_change_log_from(buffer.as_slice())
+            .unwrap();
+        assert_eq!(change_summary(&catalog), change_summary(&imported_catalog));
+        assert_eq!(None, imported_catalog.prototype_of(instance_id));
+        assert!(!imported_catalog.is_instance(instance_id));
+        assert_eq!(String::from("Atom"), imported_catalog.get(instance_id).name);
+    }
+
+    #[test]
+    fn test_import_change_log_in_lsn_order() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person::default());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        for age in 1..4 {
+            catalog.edit(proto_id, |person| person.age = age).unwrap();
+        }
+
+        let mut buffer = vec![];
+        catalog.export_change_log_to(&mut buffer).unwrap();
+        let exported = String::from_utf8(buffer).unwrap();
+        let shuffled = exported.lines().rev().collect::<Vec<_>>().join("\n");
+
+        let imported_library = Library::default();
+        imported_library.register::<Person>();
+        let imported_catalog = imported_library.checkout::<Person>();
+        assert_eq!(
+            8,
+            imported_catalog
+                .import_change_log_from(shuffled.as_bytes())
+                .unwrap()
+        );
+        assert_eq!(change_summary(&catalog), change_summary(&imported_catalog));
+        assert_eq!(3, imported_catalog.get(instance_id).age);
+        assert_eq!(library.current_lsn(), imported_library.current_lsn());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+
+        let mut buffer = vec![];
+        library.save(&mut buffer).unwrap();
+
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library.load(buffer.as_slice()).unwrap();
+        let loaded_catalog = loaded_library.checkout::<Person>();
+        assert_eq!(change_summary(&catalog), change_summary(&loaded_catalog));
+        assert_eq!(String::from("Atom"), loaded_catalog.get(instance_id).name);
+        assert_eq!(
+            SNAPSHOT_FORMAT_VERSION,
+            loaded_library.snapshot().format_version
+        );
+
+        let unregistered_library = Library::default();
+        unregistered_library.register::<Person>();
+        assert!(matches!(
+            unregistered_library.load(buffer.as_slice()),
+            Err(LoadError::UnregisteredCatalog(type_name)) if type_name == "Person"
+        ));
+    }
+
+    #[test]
+    fn test_delete_swap_save_load() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let catalog = library.checkout::<Person>();
+        let instance_id = catalog.create(Person::default());
+        let removed_id = catalog.create(Person::default());
+        let proto_id = catalog.create(Person::default());
+        catalog.reparent_prototype(instance_id, proto_id).unwrap();
+        let (_, moved_to) = catalog.delete_swap(removed_id).unwrap();
+
+        let mut buffer = vec![];
+        library.save(&mut buffer).unwrap();
+
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library.load(buffer.as_slice()).unwrap();
+        let loaded_catalog = loaded_library.checkout::<Person>();
+        assert_eq!(Some(moved_to), loaded_catalog.prototype_of(instance_id));
+        assert_eq!(
+            vec![instance_id],
+            loaded_catalog.prototype_instances(moved_to)
+        );
+        loaded_catalog
+            .edit(moved_to, |person| person.age = 12)
+            .unwrap();
+        assert_eq!(12, loaded_catalog.get(instance_id).age);
+    }
+
+    #[test]
+    fn test_load_unsupported_version() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        library.checkout::<Person>().create(Person::default());
+        let old_snapshot = LibrarySnapshot {
+            format_version: 0,
+            ..library.snapshot()
+        };
+
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        let result = loaded_library.load(serde_json::to_vec(&old_snapshot).unwrap().as_slice());
+        assert!(matches!(result, Err(LoadError::UnsupportedVersion(0))));
+        assert!(!loaded_library
+            .checkout::<Person>()
+            .contains(crate::RecordId::new(0)));
+
+        let unversioned = br#"{"catalogs":{}}"#;
+        assert!(matches!(
+            loaded_library.load(&unversioned[..]),
+            Err(LoadError::UnsupportedVersion(0))
+        ));
+    }
+
+    #[test]
+    fn test_sparse_save_load() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let catalog = library.checkout::<Person>();
+        let person_id = catalog.create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+
+        let snapshot = library.sparse_snapshot();
+        assert!(snapshot.sparse);
+        let saved_record = &snapshot.catalogs["Person"][0]["new_record"]["record"];
+        assert_eq!(Some("Atom"), saved_record["name"].as_str());
+        assert_eq!(1, saved_record.as_object().unwrap().len());
+
+        let mut buffer = vec![];
+        library.save_sparse(&mut buffer).unwrap();
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library.load(buffer.as_slice()).unwrap();
+        let loaded_catalog = loaded_library.checkout::<Person>();
+        assert_eq!(change_summary(&catalog), change_summary(&loaded_catalog));
+        let loaded_person = loaded_catalog.get(person_id);
+        assert_eq!(String::from("Atom"), loaded_person.name);
+        assert_eq!(0, loaded_person.age);
+        assert_eq!(String::new(), loaded_person.fav_food);
+    }
+
+    #[test]
+    fn test_load_version_1() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        library.checkout::<Person>().create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let mut version_1 = serde_json::to_value(library.snapshot()).unwrap();
+        version_1["format_version"] = serde_json::Value::from(1);
+        version_1.as_object_mut().unwrap().remove("sparse");
+
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library
+            .load(serde_json::to_vec(&version_1).unwrap().as_slice())
+            .unwrap();
+        assert_eq!(
+            String::from("Atom"),
+            loaded_library
+                .checkout::<Person>()
+                .get(crate::RecordId::new(0))
+                .name
+        );
+    }
+
+    #[test]
+    fn test_overrides_survive_save_load() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            age: 3,
+            ..Default::default()
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        catalog.edit(instance_id, |person| person.age = 5).unwrap();
+        catalog.edit(instance_id, |person| person.age = 3).unwrap();
+        catalog
+            .edit(proto_id, |person| person.name = String::from("Atom"))
+            .unwrap();
+        assert_eq!(vec!["age"], catalog.overridden_fields(instance_id));
+
+        let mut buffer = vec![];
+        library.save(&mut buffer).unwrap();
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library.load(buffer.as_slice()).unwrap();
+        let loaded_catalog = loaded_library.checkout::<Person>();
+        assert_eq!(vec!["age"], loaded_catalog.overridden_fields(instance_id));
+        assert!(loaded_catalog.overridden_fields(proto_id).is_empty());
+    }
+
+    #[test]
+    fn test_generations_survive_save_load() {
+        let library = Library::default();
+        library.register_serializable::<Person>();
+        let catalog = library.checkout::<Person>();
+        let ids = catalog.create_many(vec![Person::default(); 2]);
+        let (_, moved_id) = catalog.delete_swap(ids[0]).unwrap();
+        assert_eq!(
+            serde_json::json!([0, 1]),
+            serde_json::to_value(moved_id).unwrap()
+        );
+
+        let mut buffer = vec![];
+        library.save(&mut buffer).unwrap();
+        let loaded_library = Library::default();
+        loaded_library.register_serializable::<Person>();
+        loaded_library.load(buffer.as_slice()).unwrap();
+        let loaded_catalog = loaded_library.checkout::<Person>();
+        assert!(loaded_catalog.contains(moved_id));
+        assert!(!loaded_catalog.contains(ids[0]));
+        assert!(!loaded_catalog.contains(ids[1]));
+        assert_ne!(ids[1], loaded_catalog.create(Person::default()));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::catalog::Catalog;
+use crate::record::{Record, RecordId};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+// Fed every change as it's written to the change log, while the catalog's state is held.
+pub(crate) trait RecordIndex<R>: Send + Sync
+where
+    R: Record,
+{
+    fn update(&self, id: RecordId<R>, record: Option<&R>);
+}
+
+struct IndexEntries<R, K> {
+    ids_by_key: HashMap<K, HashSet<RecordId<R>>>,
+    keys_by_id: HashMap<RecordId<R>, K>,
+}
+
+impl<R, K> Default for IndexEntries<R, K> {
+    fn default() -> Self {
+        IndexEntries {
+            ids_by_key: Default::default(),
+            keys_by_id: Default::default(),
+        }
+    }
+}
+
+type KeyFn<R, K> = Box<dyn Fn(&R) -> K + Send + Sync>;
+
+struct Index<R, K> {
+    key_fn: KeyFn<R, K>,
+    entries: Mutex<IndexEntries<R, K>>,
+}
+
+impl<R, K> RecordIndex<R> for Index<R, K>
+where
+    R: Record,
+    K: Clone + Eq + Hash + Send,
+{
+    fn update(&self, id: RecordId<R>, record: Option<&R>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old_key) = entries.keys_by_id.remove(&id) {
+            if let Some(ids) = entries.ids_by_key.get_mut(&old_key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    entries.ids_by_key.remove(&old_key);
+                }
+            }
+        }
+        if let Some(record) = record {
+            let key = (self.key_fn)(record);
+            entries
+                .ids_by_key
+                .entry(key.clone())
+                .or_default()
+                .insert(id);
+            entries.keys_by_id.insert(id, key);
+        }
+    }
+}
+
+// The catalog keeps the index up to date for as long as a handle to it is around.
+pub struct IndexHandle<R, K> {
+    index: Arc<Index<R, K>>,
+}
+
+impl<R, K> IndexHandle<R, K>
+where
+    R: Record,
+    K: Clone + Eq + Hash + Send,
+{
+    // Ids of the records whose key is `key`, in id order.
+    pub fn lookup(&self, key: &K) -> Vec<RecordId<R>> {
+        let entries = self.index.entries.lock().unwrap();
+        let mut ids = entries
+            .ids_by_key
+            .get(key)
+            .map(|ids| ids.iter().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        ids.sort_unstable_by_key(|id| id.index());
+        ids
+    }
+}
+
+impl<R> Catalog<R>
+where
+    R: Record,
+{
+    // Indexes the records by `key_fn`. The index is updated from the change log, so creates,
+    // commits, prototype cascades and deletes all show up in it once they're written.
+    pub fn add_index<K, F>(&self, key_fn: F) -> IndexHandle<R, K>
+    where
+        K: Clone + Eq + Hash + Send + 'static,
+        F: Fn(&R) -> K + Send + Sync + 'static,
+    {
+        let index = Arc::from(Index {
+            key_fn: Box::new(key_fn),
+            entries: Default::default(),
+        });
+        self.state.inner.lock().unwrap().add_index(index.clone());
+        IndexHandle { index }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::Person, Library};
+
+    #[test]
+    fn test_index() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            fav_food: String::from("Kibble"),
+            ..Default::default()
+        });
+        let index = catalog.add_index(|person: &Person| person.fav_food.clone());
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let other_id = catalog.create(Person {
+            fav_food: String::from("Pizza"),
+            ..Default::default()
+        });
+        assert_eq!(
+            vec![proto_id, instance_id],
+            index.lookup(&String::from("Kibble"))
+        );
+
+        catalog
+            .edit(proto_id, |person| person.fav_food = String::from("Pizza"))
+            .unwrap();
+        assert!(index.lookup(&String::from("Kibble")).is_empty());
+        assert_eq!(
+            vec![proto_id, instance_id, other_id],
+            index.lookup(&String::from("Pizza"))
+        );
+
+        catalog.delete(other_id);
+        assert_eq!(
+            vec![proto_id, instance_id],
+            index.lookup(&String::from("Pizza"))
+        );
+    }
+}
@@ -1,10 +1,21 @@
 mod catalog;
 mod change_log;
+#[cfg(feature = "serde")]
+mod export;
+mod index;
 mod library;
+#[cfg(feature = "local")]
+mod local;
 pub mod prelude;
 mod record;
+mod sync;
 
 pub use catalog::*;
 pub use change_log::*;
+#[cfg(feature = "serde")]
+pub use export::*;
+pub use index::*;
 pub use library::*;
+#[cfg(feature = "local")]
+pub use local::*;
 pub use record::*;
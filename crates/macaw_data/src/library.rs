@@ -1,30 +1,115 @@
+#[cfg(feature = "serde")]
+use crate::export::{LoadError, WriteAheadLog};
 use crate::{
-    catalog::{Catalog, CatalogState},
-    record::Record,
+    catalog::{Catalog, CatalogState, ReadCatalog},
+    change_log::{AnyChange, Watermark},
+    record::{Record, RecordId},
 };
 use std::{
     any::Any,
     collections::HashMap,
-    fmt::Debug,
+    fmt::{self, Debug},
     marker::{Send, Sync},
+    mem,
     sync::{atomic::AtomicU64, atomic::Ordering, Arc, Mutex},
 };
 
 #[derive(Clone, Debug, Default)]
 pub struct Library {
-    pub catalogs: Arc<Mutex<HashMap<String, Arc<dyn Any + Send + Sync>>>>,
-    sequencer: Sequencer,
+    // Private since the catalogs are type-erased behind a crate-only trait. `registered_types`,
+    // `catalog_count` and `summary` cover what reading the map used to give.
+    pub(crate) catalogs: Arc<Mutex<HashMap<String, Arc<dyn AnyCatalogState>>>>,
+    pub(crate) sequencer: Sequencer,
+    generation: Arc<AtomicU64>,
+    change_listeners: ChangeListeners,
+    #[cfg(feature = "serde")]
+    pub(crate) write_ahead_log: WriteAheadLog,
+}
+
+#[derive(Debug, Default)]
+pub struct LibraryBuilder {
+    library: Library,
+}
+
+impl LibraryBuilder {
+    pub fn register<R>(self) -> LibraryBuilder
+    where
+        R: Record,
+    {
+        self.library.register::<R>();
+        self
+    }
+
+    pub fn build(self) -> Library {
+        self.library
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LibrarySummary {
+    pub type_count: usize,
+    pub records_per_type: HashMap<String, usize>,
+}
+
+pub(crate) trait AnyCatalogState: Debug + Send + Sync {
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+    fn record_count(&self) -> usize;
+    fn contains(&self, id: RecordId) -> bool;
+    fn retire(&self);
+    // Every change from `watermark` on, and the watermark after the last of them.
+    fn changes_since(&self, watermark: Watermark) -> (Vec<AnyChange>, Watermark);
+    // Changes that were compacted away are left out.
+    fn changes_between(&self, start_point: Watermark, end_point: Watermark) -> Vec<AnyChange>;
+    #[cfg(feature = "serde")]
+    fn is_serializable(&self) -> bool;
+    #[cfg(feature = "serde")]
+    fn serialize_default_record(&self) -> serde_json::Value;
+    #[cfg(feature = "serde")]
+    fn export_changes(&self) -> Vec<serde_json::Value>;
+    #[cfg(feature = "serde")]
+    fn import_changes(
+        &self,
+        changes: Vec<serde_json::Value>,
+        sequencer: &Sequencer,
+    ) -> Result<(), LoadError>;
 }
 
 impl Library {
+    pub fn builder() -> LibraryBuilder {
+        Default::default()
+    }
+
     pub fn register<R>(&self)
     where
         R: Record,
     {
-        self.catalogs.lock().unwrap().insert(
-            R::type_name().to_string(),
-            Arc::from(CatalogState::<R>::default()),
-        );
+        self.register_state::<R>(|_| {});
+    }
+
+    pub(crate) fn register_state<R>(&self, f: impl FnOnce(&mut CatalogState<R>))
+    where
+        R: Record,
+    {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut catalog_state = CatalogState::<R>::from_inner(generation, Default::default());
+        f(&mut catalog_state);
+        if let Some(old_catalog_state) = self
+            .catalogs
+            .lock()
+            .unwrap()
+            .insert(R::type_name().to_string(), Arc::from(catalog_state))
+        {
+            old_catalog_state.retire();
+        }
+    }
+
+    pub fn unregister<R>(&self)
+    where
+        R: Record,
+    {
+        if let Some(old_catalog_state) = self.catalogs.lock().unwrap().remove(R::type_name()) {
+            old_catalog_state.retire();
+        }
     }
 
     pub fn checkout<R>(&self) -> Catalog<R>
@@ -38,12 +123,167 @@ impl Library {
             .get(R::type_name())
             .unwrap()
             .clone()
+            .as_any()
             .downcast::<CatalogState<R>>()
             .unwrap();
         Catalog {
+            generation: library_catalog.generation.load(Ordering::SeqCst),
             state: library_catalog,
             reads: Default::default(),
             sequencer: self.sequencer.clone(),
+            change_listeners: self.change_listeners.clone(),
+            #[cfg(feature = "serde")]
+            write_ahead_log: self.write_ahead_log.clone(),
+            library: self.clone(),
+        }
+    }
+
+    pub fn checkout_readonly<R>(&self) -> ReadCatalog<R>
+    where
+        R: Record,
+    {
+        ReadCatalog {
+            catalog: self.checkout(),
+        }
+    }
+
+    pub fn with<R, T, F>(&self, f: F) -> T
+    where
+        R: Record,
+        F: FnOnce(&Catalog<R>) -> T,
+    {
+        f(&self.checkout::<R>())
+    }
+
+    pub fn on_any_change<F>(&self, f: F)
+    where
+        F: Fn(&'static str, RecordId, u64) + Send + Sync + 'static,
+    {
+        self.change_listeners.add(Arc::from(f));
+    }
+
+    // Calls `f` with every change made since `watermarks`, which are keyed by type name, across
+    // all catalogs in LSN order. Catalogs missing from `watermarks` start from the beginning of
+    // their change log, and changes that were already compacted away are skipped. Returns the
+    // watermarks to pass in next time.
+    pub fn for_each_change_since<F>(
+        &self,
+        watermarks: &HashMap<String, Watermark>,
+        mut f: F,
+    ) -> HashMap<String, Watermark>
+    where
+        F: FnMut(AnyChange),
+    {
+        let catalogs = self.catalogs.lock().unwrap().clone();
+        let mut changes = vec![];
+        let mut new_watermarks = HashMap::new();
+        for (type_name, catalog_state) in catalogs {
+            let watermark = watermarks.get(&type_name).copied().unwrap_or(Watermark(0));
+            let (catalog_changes, new_watermark) = catalog_state.changes_since(watermark);
+            changes.extend(catalog_changes);
+            new_watermarks.insert(type_name, new_watermark);
+        }
+        changes.sort_by_key(AnyChange::lsn);
+        for change in changes {
+            f(change);
+        }
+        new_watermarks
+    }
+
+    // Calls `f` with every change to `R` records, whether or not `R` is registered yet, with
+    // its LSN and the records before and after. Changes written to different catalogs at the
+    // same time may be delivered slightly out of LSN order, but each catalog's changes always
+    // come in order.
+    pub fn subscribe_changes<R, F>(&self, f: F)
+    where
+        R: Record,
+        F: Fn(RecordId<R>, u64, Option<&R>, Option<&R>) + Send + Sync + 'static,
+    {
+        self.change_listeners
+            .sinks
+            .sinks
+            .lock()
+            .unwrap()
+            .push(Arc::from(move |change: &AnyChange| {
+                if change.type_name() == R::type_name() {
+                    f(
+                        change.record_id().typed(),
+                        change.lsn(),
+                        change.old_record::<R>(),
+                        change.new_record::<R>(),
+                    );
+                }
+            }));
+    }
+
+    // Every change in the given `(type_name, start, end)` windows, in LSN order, for a timeline
+    // across catalogs. Types that aren't registered are skipped.
+    pub fn merged_changes(&self, windows: &[(&str, Watermark, Watermark)]) -> Vec<AnyChange> {
+        let catalogs = self.catalogs.lock().unwrap().clone();
+        let mut changes = windows
+            .iter()
+            .filter_map(|(type_name, start_point, end_point)| {
+                let catalog_state = catalogs.get(*type_name)?;
+                Some(catalog_state.changes_between(*start_point, *end_point))
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        changes.sort_by_key(AnyChange::lsn);
+        changes
+    }
+
+    pub fn deregister_all(&self) {
+        for (_, catalog_state) in self.catalogs.lock().unwrap().drain() {
+            catalog_state.retire();
+        }
+    }
+
+    // Outstanding `Catalog` handles are orphaned: they keep working against their old state,
+    // but nothing checked out afterwards will see it.
+    pub fn reset(&self) {
+        self.deregister_all();
+        self.sequencer.reset();
+        self.change_listeners.listeners.lock().unwrap().clear();
+        self.change_listeners.sinks.sinks.lock().unwrap().clear();
+    }
+
+    // Records in catalogs that aren't registered don't exist.
+    pub(crate) fn contains_record(&self, type_name: &str, id: RecordId) -> bool {
+        let catalog_state = self.catalogs.lock().unwrap().get(type_name).cloned();
+        catalog_state.is_some_and(|catalog_state| catalog_state.contains(id))
+    }
+
+    // Type names of the registered catalogs, sorted.
+    pub fn registered_types(&self) -> Vec<String> {
+        let mut type_names = self
+            .catalogs
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        type_names.sort();
+        type_names
+    }
+
+    pub fn catalog_count(&self) -> usize {
+        self.catalogs.lock().unwrap().len()
+    }
+
+    // The LSN the next change will get, without using it up. Every change written so far has a
+    // lower one, so a backup taken after reading it includes all of them.
+    pub fn current_lsn(&self) -> u64 {
+        self.sequencer.current()
+    }
+
+    pub fn summary(&self) -> LibrarySummary {
+        let catalogs = self.catalogs.lock().unwrap();
+        LibrarySummary {
+            type_count: catalogs.len(),
+            records_per_type: catalogs
+                .iter()
+                .map(|(type_name, catalog)| (type_name.clone(), catalog.record_count()))
+                .collect(),
         }
     }
 }
@@ -57,14 +297,153 @@ impl Sequencer {
     pub fn next(&self) -> u64 {
         self.next_lsn.fetch_add(1, Ordering::Relaxed)
     }
+
+    pub(crate) fn reset(&self) {
+        self.next_lsn.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn current(&self) -> u64 {
+        self.next_lsn.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn advance_past(&self, lsn: u64) {
+        self.next_lsn.fetch_max(lsn + 1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) type ChangeListener = Arc<dyn Fn(&'static str, RecordId, u64) + Send + Sync>;
+
+type ChangeSink = Arc<dyn Fn(&AnyChange) + Send + Sync>;
+
+// Changes are queued as they're written, under their catalog's state lock, and delivered to
+// the sinks once it's released. Only one thread delivers at a time, so sinks see changes in the
+// order they were queued.
+#[derive(Default)]
+struct ChangeSinks {
+    sinks: Mutex<Vec<ChangeSink>>,
+    pending: Mutex<Vec<AnyChange>>,
+    delivering: Mutex<()>,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct ChangeListeners {
+    listeners: Arc<Mutex<Vec<ChangeListener>>>,
+    sinks: Arc<ChangeSinks>,
+}
+
+impl ChangeListeners {
+    // Called with the catalog's state held. `change` is only built when there's a sink.
+    pub(crate) fn enqueue(&self, change: impl FnOnce() -> AnyChange) {
+        if !self.sinks.sinks.lock().unwrap().is_empty() {
+            self.sinks.pending.lock().unwrap().push(change());
+        }
+    }
+
+    // A thread that finds another one delivering leaves its changes to it, which also covers
+    // sinks that write to a catalog themselves.
+    pub(crate) fn deliver(&self) {
+        loop {
+            {
+                let _delivering = match self.sinks.delivering.try_lock() {
+                    Ok(delivering) => delivering,
+                    Err(_) => return,
+                };
+                loop {
+                    let mut changes = mem::take(&mut *self.sinks.pending.lock().unwrap());
+                    if changes.is_empty() {
+                        break;
+                    }
+                    changes.sort_by_key(AnyChange::lsn);
+                    let sinks = self.sinks.sinks.lock().unwrap().clone();
+                    for change in &changes {
+                        for sink in &sinks {
+                            sink(change);
+                        }
+                    }
+                }
+            }
+            // Changes queued after the last check but before `delivering` was released.
+            if self.sinks.pending.lock().unwrap().is_empty() {
+                return;
+            }
+        }
+    }
+
+    pub fn notify(&self, type_name: &'static str, id: RecordId, lsn: u64) {
+        let listeners = self.listeners.lock().unwrap().clone();
+        for listener in listeners {
+            listener(type_name, id, lsn);
+        }
+    }
+
+    pub(crate) fn add(&self, listener: ChangeListener) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    pub(crate) fn subscribe(&self, listener: ChangeListener) -> Subscription {
+        self.add(listener.clone());
+        Subscription {
+            change_listeners: self.clone(),
+            subscriber: Subscriber::Listener(listener),
+        }
+    }
+
+    pub(crate) fn subscribe_sink(&self, sink: ChangeSink) -> Subscription {
+        self.sinks.sinks.lock().unwrap().push(sink.clone());
+        Subscription {
+            change_listeners: self.clone(),
+            subscriber: Subscriber::Sink(sink),
+        }
+    }
+}
+
+enum Subscriber {
+    Listener(ChangeListener),
+    Sink(ChangeSink),
+}
+
+// Removes its change listener or sink when dropped.
+#[must_use]
+pub struct Subscription {
+    change_listeners: ChangeListeners,
+    subscriber: Subscriber,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        match &self.subscriber {
+            Subscriber::Listener(listener) => self
+                .change_listeners
+                .listeners
+                .lock()
+                .unwrap()
+                .retain(|other| !Arc::ptr_eq(other, listener)),
+            Subscriber::Sink(sink) => self
+                .change_listeners
+                .sinks
+                .sinks
+                .lock()
+                .unwrap()
+                .retain(|other| !Arc::ptr_eq(other, sink)),
+        }
+    }
+}
+
+impl Debug for ChangeListeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeListeners")
+            .field("len", &self.listeners.lock().unwrap().len())
+            .finish()
+    }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
-    use crate::{proto_update_field, Library, Record};
+    use crate::{proto_update_field, Catalog, Library, Record, RecordId};
     use rand::{distributions::Alphanumeric, Rng};
     use std::{
-        collections::HashSet,
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
         thread,
         time::{Duration, Instant},
     };
@@ -87,18 +466,20 @@ pub(crate) mod tests {
                     let library_copy = library.clone();
                     move || {
                         let person_catalog = library_copy.checkout::<Person>();
-                        let locked_person = person_catalog.lock(person_id);
+                        let locked_person = person_catalog.lock(person_id).unwrap();
                         let mut writable_person = locked_person.value.clone();
                         writable_person.age += 1;
                         thread::sleep(Duration::from_millis(1));
-                        person_catalog.commit(&locked_person, writable_person);
+                        person_catalog
+                            .commit(&locked_person, writable_person)
+                            .unwrap();
 
                         let dog_catalog = library_copy.checkout::<Dog>();
-                        let locked_dog = dog_catalog.lock(dog_id);
+                        let locked_dog = dog_catalog.lock(dog_id).unwrap();
                         let mut writable_dog = locked_dog.value.clone();
                         writable_dog.dog_years += 7;
                         thread::sleep(Duration::from_millis(1));
-                        dog_catalog.commit(&locked_dog, writable_dog);
+                        dog_catalog.commit(&locked_dog, writable_dog).unwrap();
                     }
                 })
             })
@@ -112,6 +493,276 @@ pub(crate) mod tests {
         assert_eq!(thread_count * 7, dog_catalog.get(dog_id).dog_years);
     }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stale catalog handle")]
+    fn test_stale_catalog_handle() {
+        let library = Library::default();
+        library.register::<Person>();
+        let stale_catalog = library.checkout::<Person>();
+        let id = stale_catalog.create(Person::default());
+
+        library.unregister::<Person>();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        catalog.create(Person::default());
+
+        stale_catalog
+            .lock(id)
+            .unwrap()
+            .mutate(|person| person.age = 1)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_builder() {
+        let library = Library::builder()
+            .register::<Person>()
+            .register::<Dog>()
+            .build();
+
+        let person_id = library.checkout::<Person>().create(Person::default());
+        let dog_id = library.checkout::<Dog>().create(Dog::default());
+        assert_eq!(0, library.checkout::<Person>().get(person_id).age);
+        assert_eq!(0, library.checkout::<Dog>().get(dog_id).dog_years);
+    }
+
+    #[test]
+    fn test_on_any_change() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        library.on_any_change({
+            let seen = seen.clone();
+            move |type_name, id, lsn| seen.lock().unwrap().push((type_name, id, lsn))
+        });
+
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+        let person_id = person_catalog.create(Person::default());
+        let dog_id = dog_catalog.create(Dog::default());
+        dog_catalog
+            .lock(dog_id)
+            .unwrap()
+            .mutate(|dog| dog.dog_years = 7)
+            .unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            vec![
+                ("Person", person_id.untyped()),
+                ("Dog", dog_id.untyped()),
+                ("Dog", dog_id.untyped())
+            ],
+            seen.iter()
+                .map(|(type_name, id, _)| (*type_name, *id))
+                .collect::<Vec<_>>()
+        );
+        assert!(seen.windows(2).all(|pair| pair[0].2 < pair[1].2));
+    }
+
+    #[test]
+    fn test_summary() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+
+        let person_catalog = library.checkout::<Person>();
+        let proto_id = person_catalog.create(Person::default());
+        person_catalog.create_from_prototype(proto_id);
+        person_catalog.create(Person::default());
+        library.checkout::<Dog>().create(Dog::default());
+
+        let summary = library.summary();
+        assert_eq!(2, library.catalog_count());
+        assert_eq!(2, summary.type_count);
+        assert_eq!(Some(&3), summary.records_per_type.get("Person"));
+        assert_eq!(Some(&1), summary.records_per_type.get("Dog"));
+    }
+
+    #[test]
+    fn test_current_lsn() {
+        let library = Library::default();
+        library.register::<Person>();
+        assert_eq!(0, library.current_lsn());
+
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+        catalog.edit(id, |person| person.age = 1).unwrap();
+        catalog.edit(id, |person| person.age = 2).unwrap();
+        assert_eq!(3, library.current_lsn());
+        assert_eq!(3, library.current_lsn());
+    }
+
+    #[test]
+    fn test_for_each_change_since() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+        let person_id = person_catalog.create(Person::default());
+        let dog_id = dog_catalog.create(Dog::default());
+        person_catalog
+            .edit(person_id, |person| person.age = 1)
+            .unwrap();
+        dog_catalog.edit(dog_id, |dog| dog.dog_years = 7).unwrap();
+
+        let mut seen = vec![];
+        let watermarks = library.for_each_change_since(&HashMap::new(), |change| {
+            seen.push((change.type_name(), change.record_id(), change.lsn()))
+        });
+        assert_eq!(
+            vec![
+                ("Person", person_id.untyped(), 0),
+                ("Dog", dog_id.untyped(), 1),
+                ("Person", person_id.untyped(), 2),
+                ("Dog", dog_id.untyped(), 3),
+            ],
+            seen
+        );
+        assert_eq!(Some(&person_catalog.watermark()), watermarks.get("Person"));
+
+        dog_catalog.edit(dog_id, |dog| dog.dog_years = 14).unwrap();
+        let mut seen = vec![];
+        library.for_each_change_since(&watermarks, |change| {
+            assert!(change.new_record::<Person>().is_none());
+            seen.push((
+                change.old_record::<Dog>().unwrap().dog_years,
+                change.new_record::<Dog>().unwrap().dog_years,
+            ))
+        });
+        assert_eq!(vec![(7, 14)], seen);
+    }
+
+    #[test]
+    fn test_subscribe_changes() {
+        let library = Library::default();
+        let seen = Arc::new(Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            library.subscribe_changes(
+                move |id: RecordId<Person>, lsn, old: Option<&Person>, new: Option<&Person>| {
+                    seen.lock().unwrap().push((
+                        id,
+                        lsn,
+                        old.map(|person| person.age),
+                        new.map(|person| person.age),
+                    ))
+                },
+            );
+        }
+        library.register::<Person>();
+        library.register::<Dog>();
+
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+        let proto_id = person_catalog.create(Person::default());
+        dog_catalog.create(Dog::default());
+        let instance_id = person_catalog.create_from_prototype(proto_id);
+        person_catalog
+            .edit(proto_id, |person| person.age = 5)
+            .unwrap();
+        person_catalog.delete(instance_id);
+
+        assert_eq!(
+            vec![
+                (proto_id, 0, None, Some(0)),
+                (instance_id, 2, None, Some(0)),
+                (proto_id, 3, Some(0), Some(5)),
+                (instance_id, 4, Some(0), Some(5)),
+                (instance_id, 5, Some(5), None),
+            ],
+            *seen.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merged_changes() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+        let person_id = person_catalog.create(Person::default());
+        let person_start = person_catalog.watermark();
+        let dog_start = dog_catalog.watermark();
+        let dog_id = dog_catalog.create(Dog::default());
+        person_catalog
+            .edit(person_id, |person| person.age = 3)
+            .unwrap();
+        dog_catalog.edit(dog_id, |dog| dog.dog_years = 21).unwrap();
+
+        let changes = library.merged_changes(&[
+            ("Dog", dog_start, dog_catalog.watermark()),
+            ("Person", person_start, person_catalog.watermark()),
+            ("Cat", dog_start, dog_catalog.watermark()),
+        ]);
+        assert_eq!(
+            vec![("Dog", 1), ("Person", 2), ("Dog", 3)],
+            changes
+                .iter()
+                .map(|change| (change.type_name(), change.lsn()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(3),
+            changes[1].new_record::<Person>().map(|person| person.age)
+        );
+        assert!(format!("{:?}", changes[2]).contains("dog_years: 21"));
+    }
+
+    #[test]
+    fn test_with() {
+        let library = Library::default();
+        library.register::<Person>();
+
+        let total_age = library.with(|catalog: &Catalog<Person>| {
+            let proto_id = catalog.create(Person::default());
+            let ids = (0..10)
+                .map(|_| catalog.create_from_prototype(proto_id))
+                .collect::<Vec<_>>();
+            catalog
+                .lock(proto_id)
+                .unwrap()
+                .mutate(|person| person.age = 3)
+                .unwrap();
+            ids.iter().map(|id| catalog.get(*id).age).sum::<i32>()
+        });
+
+        assert_eq!(30, total_age);
+        assert_eq!(Some(&11), library.summary().records_per_type.get("Person"));
+    }
+
+    #[test]
+    fn test_reset() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        assert_eq!(vec!["Dog", "Person"], library.registered_types());
+
+        let person_catalog = library.checkout::<Person>();
+        person_catalog.create(Person::default());
+        library.reset();
+        assert!(library.registered_types().is_empty());
+        assert_eq!(0, library.catalog_count());
+
+        library.register::<Person>();
+        let person_catalog = library.checkout::<Person>();
+        let start_watermark = person_catalog.watermark();
+        let id = person_catalog.create(Person::default());
+        assert_eq!(RecordId::new(0), id);
+        assert_eq!(
+            Some(0),
+            person_catalog
+                .changes(start_watermark, person_catalog.watermark())
+                .next()
+                .map(|change| change.lsn())
+        );
+    }
+
     #[test]
     fn test_prototypes() {
         let library = Library::default();
@@ -128,10 +779,10 @@ pub(crate) mod tests {
         assert_eq!(String::from("Atom"), catalog.get(instance_id).name);
 
         {
-            let person = catalog.lock(instance_id);
+            let person = catalog.lock(instance_id).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("Eva");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
         }
 
         assert_eq!(String::from("Atom"), catalog.get(proto_id).name);
@@ -148,16 +799,16 @@ pub(crate) mod tests {
         let daughter_id = catalog.create_from_prototype(mother_id);
 
         {
-            let grandmother = catalog.lock(grandmother_id);
+            let grandmother = catalog.lock(grandmother_id).unwrap();
             let mut write = grandmother.value.clone();
             write.name = String::from("Grandma");
             write.fav_food = String::from("Old Timey Pasta");
-            catalog.commit(&grandmother, write);
+            catalog.commit(&grandmother, write).unwrap();
 
-            let mother = catalog.lock(mother_id);
+            let mother = catalog.lock(mother_id).unwrap();
             let mut write = grandmother.value.clone();
             write.fav_food = String::from("Pasta");
-            catalog.commit(&mother, write);
+            catalog.commit(&mother, write).unwrap();
         }
 
         // One thread that will constantly update the grandmother's age and check that
@@ -170,10 +821,10 @@ pub(crate) mod tests {
                 let start = Instant::now();
                 while start.elapsed() < Duration::from_millis(50) {
                     let rand_age = rng.gen::<i32>();
-                    let grandmother = catalog.lock(grandmother_id);
+                    let grandmother = catalog.lock(grandmother_id).unwrap();
                     let mut write = grandmother.value.clone();
                     write.age = rand_age;
-                    catalog.commit(&grandmother, write);
+                    catalog.commit(&grandmother, write).unwrap();
                     thread::sleep(Duration::from_millis(1));
                     assert_eq!(rand_age, catalog.get(grandmother_id).age);
                     assert_eq!(rand_age, catalog.get(mother_id).age);
@@ -196,10 +847,10 @@ pub(crate) mod tests {
                         .take(10)
                         .map(char::from)
                         .collect();
-                    let mother = catalog.lock(mother_id);
+                    let mother = catalog.lock(mother_id).unwrap();
                     let mut write = mother.value.clone();
                     write.name = rand_name.clone();
-                    catalog.commit(&mother, write);
+                    catalog.commit(&mother, write).unwrap();
                     thread::sleep(Duration::from_millis(1));
                     assert_eq!(String::from("Grandma"), catalog.get(grandmother_id).name);
                     assert_eq!(rand_name, catalog.get(mother_id).name);
@@ -221,10 +872,10 @@ pub(crate) mod tests {
                         .take(10)
                         .map(char::from)
                         .collect();
-                    let daughter = catalog.lock(daughter_id);
+                    let daughter = catalog.lock(daughter_id).unwrap();
                     let mut write = daughter.value.clone();
                     write.fav_food = rand_food.clone();
-                    catalog.commit(&daughter, write);
+                    catalog.commit(&daughter, write).unwrap();
                     thread::sleep(Duration::from_millis(1));
                     assert_eq!(
                         String::from("Old Timey Pasta"),
@@ -266,10 +917,12 @@ pub(crate) mod tests {
                 let start = Instant::now();
                 while start.elapsed() < Duration::from_millis(50) {
                     let person_catalog = library_copy.checkout::<Person>();
-                    let locked_person = person_catalog.lock(person_id);
+                    let locked_person = person_catalog.lock(person_id).unwrap();
                     let mut writable_person = locked_person.value.clone();
                     writable_person.age += 1;
-                    person_catalog.commit(&locked_person, writable_person);
+                    person_catalog
+                        .commit(&locked_person, writable_person)
+                        .unwrap();
                 }
             }
         });
@@ -280,10 +933,10 @@ pub(crate) mod tests {
                 let start = Instant::now();
                 while start.elapsed() < Duration::from_millis(50) {
                     let dog_catalog = library_copy.checkout::<Dog>();
-                    let locked_dog = dog_catalog.lock(dog_id);
+                    let locked_dog = dog_catalog.lock(dog_id).unwrap();
                     let mut writable_dog = locked_dog.value.clone();
                     writable_dog.dog_years += 7;
-                    dog_catalog.commit(&locked_dog, writable_dog);
+                    dog_catalog.commit(&locked_dog, writable_dog).unwrap();
                 }
             }
         });
@@ -315,13 +968,14 @@ pub(crate) mod tests {
         }
 
         fn proto_update(&self, old: &Dog, new: &Dog) -> Dog {
-            return Dog {
+            Dog {
                 dog_years: *proto_update_field(&self.dog_years, &old.dog_years, &new.dog_years),
-            };
+            }
         }
     }
 
     #[derive(Clone, Debug, Default)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub(crate) struct Person {
         pub(crate) age: i32,
         pub(crate) name: String,
@@ -333,11 +987,29 @@ pub(crate) mod tests {
         }
 
         fn proto_update(&self, old: &Person, new: &Person) -> Person {
-            return Person {
+            Person {
                 age: *proto_update_field(&self.age, &old.age, &new.age),
                 name: proto_update_field(&self.name, &old.name, &new.name).clone(),
                 fav_food: proto_update_field(&self.fav_food, &old.fav_food, &new.fav_food).clone(),
-            };
+            }
+        }
+
+        fn changed_fields(&self, other: &Person) -> Vec<&'static str> {
+            let mut changed_fields = vec![];
+            if self.age != other.age {
+                changed_fields.push("age");
+            }
+            if self.name != other.name {
+                changed_fields.push("name");
+            }
+            if self.fav_food != other.fav_food {
+                changed_fields.push("fav_food");
+            }
+            changed_fields
+        }
+
+        fn field_names() -> &'static [&'static str] {
+            &["age", "name", "fav_food"]
         }
     }
 }
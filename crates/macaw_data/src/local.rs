@@ -0,0 +1,392 @@
+use crate::{change_log::Watermark, record::RecordId};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    rc::Rc,
+};
+
+// Single-threaded counterparts of `Record`, `Library` and `Catalog` for records that can't be
+// `Send + Sync`, e.g. ones holding `Rc`s. They cover the core record, prototype and change log
+// API; listeners, validation and the rest of the threaded extras aren't mirrored.
+pub trait LocalRecord: 'static + Clone + Debug + Default {
+    fn type_name() -> &'static str;
+    fn proto_update(&self, old_prototype: &Self, new_prototype: &Self) -> Self;
+}
+
+#[derive(Clone, Default)]
+pub struct LocalLibrary {
+    catalogs: Rc<RefCell<HashMap<String, Rc<dyn Any>>>>,
+    next_lsn: Rc<Cell<u64>>,
+}
+
+impl LocalLibrary {
+    pub fn register<R>(&self)
+    where
+        R: LocalRecord,
+    {
+        self.catalogs.borrow_mut().insert(
+            R::type_name().to_string(),
+            Rc::new(RefCell::new(LocalCatalogState::<R>::default())),
+        );
+    }
+
+    pub fn unregister<R>(&self)
+    where
+        R: LocalRecord,
+    {
+        self.catalogs.borrow_mut().remove(R::type_name());
+    }
+
+    pub fn checkout<R>(&self) -> LocalCatalog<R>
+    where
+        R: LocalRecord,
+    {
+        let state = self
+            .catalogs
+            .borrow()
+            .get(R::type_name())
+            .unwrap()
+            .clone()
+            .downcast::<RefCell<LocalCatalogState<R>>>()
+            .unwrap();
+        LocalCatalog {
+            state,
+            next_lsn: self.next_lsn.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LocalRecordWrapper<R> {
+    prototype_id: Option<RecordId<R>>,
+    prototype_instances: HashSet<RecordId<R>>,
+    inner: Rc<R>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LocalChange<R> {
+    record_id: RecordId<R>,
+    cause: Option<RecordId<R>>,
+    lsn: u64,
+    old_record: Option<Rc<R>>,
+    new_record: Option<Rc<R>>,
+}
+
+impl<R> LocalChange<R> {
+    pub fn record_id(&self) -> RecordId<R> {
+        self.record_id
+    }
+
+    pub fn cause(&self) -> Option<RecordId<R>> {
+        self.cause
+    }
+
+    pub fn old_record(&self) -> Option<&R> {
+        self.old_record.as_deref()
+    }
+
+    pub fn new_record(&self) -> Option<&R> {
+        self.new_record.as_deref()
+    }
+
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+}
+
+#[derive(Debug)]
+struct LocalCatalogState<R> {
+    records: Vec<Option<LocalRecordWrapper<R>>>,
+    locks: Vec<bool>,
+    change_log: Vec<LocalChange<R>>,
+}
+
+impl<R> Default for LocalCatalogState<R> {
+    fn default() -> Self {
+        LocalCatalogState {
+            records: Default::default(),
+            locks: Default::default(),
+            change_log: Default::default(),
+        }
+    }
+}
+
+impl<R> LocalCatalogState<R>
+where
+    R: LocalRecord,
+{
+    fn record(&self, id: RecordId<R>) -> &LocalRecordWrapper<R> {
+        self.records
+            .get(id.0)
+            .and_then(Option::as_ref)
+            .unwrap_or_else(|| panic!("{} record {:?} does not exist", R::type_name(), id))
+    }
+
+    fn record_mut(&mut self, id: RecordId<R>) -> &mut LocalRecordWrapper<R> {
+        self.records
+            .get_mut(id.0)
+            .and_then(Option::as_mut)
+            .unwrap_or_else(|| panic!("{} record {:?} does not exist", R::type_name(), id))
+    }
+}
+
+pub struct LocalCatalog<R>
+where
+    R: LocalRecord,
+{
+    state: Rc<RefCell<LocalCatalogState<R>>>,
+    next_lsn: Rc<Cell<u64>>,
+}
+
+pub struct LocalLocked<'a, R>
+where
+    R: LocalRecord,
+{
+    pub id: RecordId<R>,
+    pub value: Rc<R>,
+    catalog: &'a LocalCatalog<R>,
+}
+
+impl<'a, R> LocalLocked<'a, R>
+where
+    R: LocalRecord,
+{
+    pub fn mutate<F>(&self, f: F)
+    where
+        F: FnOnce(&mut R),
+    {
+        let mut new_record = R::clone(&self.value);
+        f(&mut new_record);
+        self.catalog.commit(self, new_record);
+    }
+}
+
+impl<'a, R> Drop for LocalLocked<'a, R>
+where
+    R: LocalRecord,
+{
+    fn drop(&mut self) {
+        if let Some(lock) = self.catalog.state.borrow_mut().locks.get_mut(self.id.0) {
+            *lock = false;
+        }
+    }
+}
+
+impl<R> LocalCatalog<R>
+where
+    R: LocalRecord,
+{
+    pub fn create(&self, record: R) -> RecordId<R> {
+        self.create_internal(None, record)
+    }
+
+    pub fn create_from_prototype(&self, prototype_id: RecordId<R>) -> RecordId<R> {
+        let instance = R::clone(&self.state.borrow().record(prototype_id).inner);
+        let instance_id = self.create_internal(Some(prototype_id), instance);
+        self.state
+            .borrow_mut()
+            .record_mut(prototype_id)
+            .prototype_instances
+            .insert(instance_id);
+        instance_id
+    }
+
+    fn create_internal(&self, prototype_id: Option<RecordId<R>>, record: R) -> RecordId<R> {
+        let mut state = self.state.borrow_mut();
+        let id = RecordId::new(state.records.len());
+        let record = Rc::new(record);
+        state.records.push(Some(LocalRecordWrapper {
+            prototype_id,
+            prototype_instances: Default::default(),
+            inner: record.clone(),
+        }));
+        state.locks.push(false);
+        self.append_change_log(&mut state, id, None, None, Some(record));
+        id
+    }
+
+    pub fn get(&self, id: RecordId<R>) -> Rc<R> {
+        self.state.borrow().record(id).inner.clone()
+    }
+
+    pub fn contains(&self, id: RecordId<R>) -> bool {
+        matches!(self.state.borrow().records.get(id.0), Some(Some(_)))
+    }
+
+    // There's no other thread to wait for, so locking a record that's already locked panics.
+    pub fn lock(&self, id: RecordId<R>) -> LocalLocked<'_, R> {
+        let mut state = self.state.borrow_mut();
+        let value = state.record(id).inner.clone();
+        assert!(
+            !state.locks[id.0],
+            "{} record {:?} is already locked",
+            R::type_name(),
+            id
+        );
+        state.locks[id.0] = true;
+        LocalLocked {
+            id,
+            value,
+            catalog: self,
+        }
+    }
+
+    pub fn is_locked(&self, id: RecordId<R>) -> bool {
+        self.state.borrow().locks[id.0]
+    }
+
+    pub fn commit(&self, locked: &LocalLocked<R>, new_record: R) {
+        let mut state = self.state.borrow_mut();
+        self.commit_locked(&mut state, locked.id, None, new_record);
+    }
+
+    fn commit_locked(
+        &self,
+        state: &mut LocalCatalogState<R>,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        new_record: R,
+    ) {
+        let new_record = Rc::new(new_record);
+        let record_wrapper = state.record_mut(id);
+        let old_record = std::mem::replace(&mut record_wrapper.inner, new_record.clone());
+        let mut instance_ids = record_wrapper
+            .prototype_instances
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        self.append_change_log(
+            state,
+            id,
+            cause,
+            Some(old_record.clone()),
+            Some(new_record.clone()),
+        );
+
+        instance_ids.sort_unstable_by_key(|instance_id| instance_id.0);
+        for instance_id in instance_ids {
+            if let Some(Some(instance_wrapper)) = state.records.get(instance_id.0) {
+                if instance_wrapper.prototype_id == Some(id) {
+                    let new_instance = instance_wrapper
+                        .inner
+                        .proto_update(&old_record, &new_record);
+                    self.commit_locked(state, instance_id, Some(id), new_instance);
+                }
+            }
+        }
+    }
+
+    // Like `Catalog::delete`, deleting a prototype detaches its instances.
+    pub fn delete(&self, id: RecordId<R>) {
+        let mut state = self.state.borrow_mut();
+        let record_wrapper = state
+            .records
+            .get_mut(id.0)
+            .and_then(Option::take)
+            .unwrap_or_else(|| panic!("{} record {:?} does not exist", R::type_name(), id));
+        if let Some(prototype_id) = record_wrapper.prototype_id {
+            if let Some(Some(prototype_wrapper)) = state.records.get_mut(prototype_id.0) {
+                prototype_wrapper.prototype_instances.remove(&id);
+            }
+        }
+        for instance_id in &record_wrapper.prototype_instances {
+            if let Some(Some(instance_wrapper)) = state.records.get_mut(instance_id.0) {
+                if instance_wrapper.prototype_id == Some(id) {
+                    instance_wrapper.prototype_id = None;
+                }
+            }
+        }
+        state.locks[id.0] = false;
+        self.append_change_log(&mut state, id, None, Some(record_wrapper.inner), None);
+    }
+
+    pub fn watermark(&self) -> Watermark {
+        Watermark(self.state.borrow().change_log.len())
+    }
+
+    pub fn changes(&self, start_point: Watermark, end_point: Watermark) -> Vec<LocalChange<R>> {
+        let state = self.state.borrow();
+        let end = end_point.0.min(state.change_log.len());
+        state.change_log[start_point.0.min(end)..end].to_vec()
+    }
+
+    fn append_change_log(
+        &self,
+        state: &mut LocalCatalogState<R>,
+        id: RecordId<R>,
+        cause: Option<RecordId<R>>,
+        old_record: Option<Rc<R>>,
+        new_record: Option<Rc<R>>,
+    ) {
+        let lsn = self.next_lsn.get();
+        self.next_lsn.set(lsn + 1);
+        state.change_log.push(LocalChange {
+            record_id: id,
+            cause,
+            lsn,
+            old_record,
+            new_record,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{proto_update_field, LocalLibrary, LocalRecord};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_local_library() {
+        let library = LocalLibrary::default();
+        library.register::<Sprite>();
+        let catalog = library.checkout::<Sprite>();
+        let start_watermark = catalog.watermark();
+        let proto_id = catalog.create(Sprite {
+            texture: Rc::new(String::from("hero.png")),
+            frame: 0,
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let overridden_id = catalog.create_from_prototype(proto_id);
+        catalog
+            .lock(overridden_id)
+            .mutate(|sprite| sprite.texture = Rc::new(String::from("villain.png")));
+
+        catalog.lock(proto_id).mutate(|sprite| {
+            sprite.texture = Rc::new(String::from("hero_hd.png"));
+            sprite.frame = 3;
+        });
+
+        assert_eq!("hero_hd.png", catalog.get(instance_id).texture.as_str());
+        assert_eq!(3, catalog.get(instance_id).frame);
+        assert_eq!("villain.png", catalog.get(overridden_id).texture.as_str());
+        assert_eq!(3, catalog.get(overridden_id).frame);
+
+        catalog.delete(proto_id);
+        assert!(!catalog.contains(proto_id));
+        let changes = catalog.changes(start_watermark, catalog.watermark());
+        assert_eq!(8, changes.len());
+        assert_eq!(Some(proto_id), changes[5].cause());
+        assert!(changes[7].new_record().is_none());
+        assert!(!catalog.is_locked(instance_id));
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Sprite {
+        texture: Rc<String>,
+        frame: u32,
+    }
+    impl LocalRecord for Sprite {
+        fn type_name() -> &'static str {
+            "Sprite"
+        }
+
+        fn proto_update(&self, old: &Sprite, new: &Sprite) -> Sprite {
+            Sprite {
+                texture: proto_update_field(&self.texture, &old.texture, &new.texture).clone(),
+                frame: *proto_update_field(&self.frame, &old.frame, &new.frame),
+            }
+        }
+    }
+}
@@ -1,5 +1,8 @@
 pub use crate::{
-    catalog::Catalog,
+    catalog::{Catalog, ReadCatalog},
     library::Library,
-    record::{proto_update_field, Locked, Record},
+    record::{
+        proto_update_field, proto_update_field_partial_eq, Locked, Record, RecordRef,
+        ValidationError,
+    },
 };
@@ -1,17 +1,295 @@
-use crate::catalog::Catalog;
+use crate::{catalog::Catalog, library::Library, sync::Mutex};
 use std::{
     collections::HashSet,
-    fmt::Debug,
-    marker::{Send, Sync},
-    sync::Mutex,
+    error::Error,
+    fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
+    marker::{PhantomData, Send, Sync},
+    ops::Deref,
+    sync::Arc,
 };
 
+// Ids are typed by the record they point at, so one catalog's ids can't be handed to another.
+// Places that deal with every catalog at once, like change listeners, use `RecordId<AnyRecord>`
+// and pair it with the catalog's type name.
+//
+// Besides its slot index, an id holds the generation of the slot it was handed out for. Every
+// delete moves the slot on to the next generation, so an id kept past a delete never reaches a
+// record created in the same slot afterwards.
+pub struct RecordId<R = AnyRecord>(pub(crate) usize, pub(crate) u32, PhantomData<fn() -> R>);
+
+// Stands in for the record type of ids that aren't tied to one catalog.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
-pub struct RecordId(pub usize);
+pub enum AnyRecord {}
+
+impl<R> RecordId<R> {
+    pub const INVALID: RecordId<R> = RecordId(usize::MAX, 0, PhantomData);
+
+    pub const fn new(index: usize) -> Self {
+        RecordId(index, 0, PhantomData)
+    }
+
+    pub(crate) const fn with_generation(index: usize, generation: u32) -> Self {
+        RecordId(index, generation, PhantomData)
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.1
+    }
+
+    pub fn is_valid(&self) -> bool {
+        *self != RecordId::INVALID
+    }
+
+    pub fn untyped(self) -> RecordId {
+        RecordId::with_generation(self.0, self.1)
+    }
+}
+
+impl RecordId {
+    pub fn typed<R>(self) -> RecordId<R> {
+        RecordId::with_generation(self.0, self.1)
+    }
+}
+
+impl<R> Copy for RecordId<R> {}
+
+impl<R> Clone for RecordId<R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R> PartialEq for RecordId<R> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.0, self.1) == (other.0, other.1)
+    }
+}
+
+impl<R> Eq for RecordId<R> {}
+
+impl<R> Hash for RecordId<R> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0, self.1).hash(state)
+    }
+}
+
+impl<R> Debug for RecordId<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            0 => write!(f, "RecordId({})", self.0),
+            generation => write!(f, "RecordId({}@{})", self.0, generation),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<R> serde::Serialize for RecordId<R> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self.1 {
+            0 => self.0.serialize(serializer),
+            generation => (self.0, generation).serialize(serializer),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, R> serde::Deserialize<'de> for RecordId<R> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Ids of generation 0 are stored as a bare index, the others as `[index, generation]`.
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum SerializedRecordId {
+            Index(usize),
+            Generational(usize, u32),
+        }
+
+        Ok(match SerializedRecordId::deserialize(deserializer)? {
+            SerializedRecordId::Index(index) => RecordId::new(index),
+            SerializedRecordId::Generational(index, generation) => {
+                RecordId::with_generation(index, generation)
+            }
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IdError {
+    Invalid,
+    Occupied(RecordId),
+}
+
+impl Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdError::Invalid => write!(f, "RecordId::INVALID can't hold a record"),
+            IdError::Occupied(id) => write!(f, "record {:?} is already occupied", id),
+        }
+    }
+}
+
+impl Error for IdError {}
+
+// Returned by `Catalog::lock` once `Catalog::shutdown` has been called.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Shutdown;
+
+impl Display for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "catalog was shut down")
+    }
+}
+
+impl Error for Shutdown {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidationError(pub String);
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid record: {}", self.0)
+    }
+}
+
+impl Error for ValidationError {}
+
+// Returned by `Catalog::edit` and `Catalog::transaction`, which lock and commit in one call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EditError {
+    Shutdown,
+    Invalid(ValidationError),
+}
+
+impl From<Shutdown> for EditError {
+    fn from(_: Shutdown) -> Self {
+        EditError::Shutdown
+    }
+}
+
+impl From<ValidationError> for EditError {
+    fn from(error: ValidationError) -> Self {
+        EditError::Invalid(error)
+    }
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::Shutdown => Display::fmt(&Shutdown, f),
+            EditError::Invalid(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl Error for EditError {}
+
+// Returned by `Catalog::reparent_prototype`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReparentError {
+    // The new prototype is the record itself or one of its instances, at any depth.
+    Cycle,
+    // The new prototype doesn't exist, or was deleted before it could be locked.
+    Missing(RecordId),
+    Invalid(ValidationError),
+}
+
+impl Display for ReparentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReparentError::Cycle => write!(f, "a record can't derive from itself"),
+            ReparentError::Missing(id) => write!(f, "prototype record {:?} does not exist", id),
+            ReparentError::Invalid(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl Error for ReparentError {}
 
 pub trait Record: 'static + Clone + Debug + Default + Send + Sync {
     fn type_name() -> &'static str;
     fn proto_update(&self, old_prototype: &Self, new_prototype: &Self) -> Self;
+
+    /// Like `proto_update`, but with the library at hand so related records in other catalogs
+    /// can be looked up. It runs while this catalog's state is held mid-commit, so it may only
+    /// read: `get`, `peek`, `get_checked` and `contains` on any catalog are fine, but locking,
+    /// committing, creating or deleting in any catalog can deadlock.
+    fn proto_update_in_library(
+        &self,
+        old_prototype: &Self,
+        new_prototype: &Self,
+        _library: &Library,
+    ) -> Self {
+        self.proto_update(old_prototype, new_prototype)
+    }
+
+    /// Checked before a commit is written, on the committed record and on every instance value
+    /// its prototype cascade would write. If any of them fails, nothing is written.
+    fn validate(&self) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    /// Whether `self` and `other` hold the same content, so committing one over the other
+    /// would be a no-op. Defaults to `==`; override it to leave out fields that shouldn't count
+    /// as a change, like a cached timestamp. Only records that implement `PartialEq` have it,
+    /// so `Catalog::commit_if_changed` is only available for them.
+    fn content_eq(&self, other: &Self) -> bool
+    where
+        Self: PartialEq,
+    {
+        self == other
+    }
+
+    /// Ids of the records this one refers to, each with the type name of the catalog it lives
+    /// in. `Catalog::find_dangling` uses them. Records that don't implement this refer to
+    /// nothing.
+    fn referenced_ids(&self) -> Vec<(&'static str, RecordId)> {
+        vec![]
+    }
+
+    /// The copy of a record kept in the change log and on the undo stack, written on every
+    /// change. Override it to leave out what's costly to keep and can be rebuilt, like a cache.
+    /// Whatever it strips is missing from everything read back from history too: the old and
+    /// new records of `Change`, `net_changes` and `diff`, exported logs and saves, and the
+    /// values undo and redo restore.
+    fn snapshot_for_history(&self) -> Self {
+        self.clone()
+    }
+
+    /// Names of the fields that differ between `self` and `other`. Records that don't
+    /// implement this report no differing fields.
+    fn changed_fields(&self, _other: &Self) -> Vec<&'static str> {
+        vec![]
+    }
+
+    /// Names of all the record's fields, in declaration order. Instances only remember
+    /// overrides, see `Catalog::overridden_fields`, for the first 64 fields listed here.
+    /// Records that don't implement this never report overrides.
+    fn field_names() -> &'static [&'static str] {
+        &[]
+    }
+}
+
+// Overrides are kept as a bitset over `Record::field_names`, so they serialize compactly.
+pub(crate) fn field_mask<R>(fields: &[&'static str]) -> u64
+where
+    R: Record,
+{
+    R::field_names()
+        .iter()
+        .take(64)
+        .enumerate()
+        .filter(|(_, field_name)| fields.contains(field_name))
+        .fold(0, |mask, (index, _)| mask | 1 << index)
 }
 
 #[derive(Debug)]
@@ -19,19 +297,97 @@ pub(crate) struct RecordWrapper<R>
 where
     R: Record,
 {
-    pub(crate) prototype_id: Option<RecordId>,
-    pub(crate) prototype_instances: Mutex<HashSet<RecordId>>,
+    pub(crate) prototype_id: Option<RecordId<R>>,
+    pub(crate) prototype_instances: Mutex<HashSet<RecordId<R>>>,
+    pub(crate) override_mask: u64,
+    // The generation of the slot the record lives in, see `RecordId`.
+    pub(crate) generation: u32,
     pub(crate) inner: R,
 }
 
+impl<R> RecordWrapper<R>
+where
+    R: Record,
+{
+    pub(crate) fn id_at(&self, index: usize) -> RecordId<R> {
+        RecordId::with_generation(index, self.generation)
+    }
+
+    // Fields committed directly to an instance stay overridden from then on, even when set
+    // back to the prototype's value. Records that aren't instances have no overrides.
+    pub(crate) fn override_mask_after(&self, new_record: &R) -> u64 {
+        match self.prototype_id {
+            Some(_) => {
+                self.override_mask | field_mask::<R>(&new_record.changed_fields(&self.inner))
+            }
+            None => 0,
+        }
+    }
+
+    pub(crate) fn overridden_fields(&self) -> Vec<&'static str> {
+        R::field_names()
+            .iter()
+            .take(64)
+            .enumerate()
+            .filter(|(index, _)| self.override_mask & 1 << index != 0)
+            .map(|(_, field_name)| *field_name)
+            .collect()
+    }
+}
+
+// Keeps the record it was read at alive, so it stays valid after later commits without
+// borrowing the catalog.
+#[derive(Clone)]
+pub struct RecordRef<R>
+where
+    R: Record,
+{
+    pub(crate) record_wrapper: Arc<RecordWrapper<R>>,
+}
+
+impl<R> Deref for RecordRef<R>
+where
+    R: Record,
+{
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.record_wrapper.inner
+    }
+}
+
+impl<R> Debug for RecordRef<R>
+where
+    R: Record,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.record_wrapper.inner.fmt(f)
+    }
+}
+
 pub struct Locked<'a, R>
 where
     R: Record,
 {
-    pub id: RecordId,
+    pub id: RecordId<R>,
     pub value: &'a R,
     pub(crate) catalog: &'a Catalog<R>,
 }
+
+impl<'a, R> Locked<'a, R>
+where
+    R: Record,
+{
+    pub fn mutate<F>(&self, f: F) -> Result<(), ValidationError>
+    where
+        F: FnOnce(&mut R),
+    {
+        let mut new_record = self.value.clone();
+        f(&mut new_record);
+        self.catalog.commit(self, new_record)
+    }
+}
+
 impl<'a, R> Drop for Locked<'a, R>
 where
     R: Record,
@@ -55,3 +411,207 @@ where
         new_prototype_field
     }
 }
+
+/// Like `proto_update_field`, but only requires `PartialEq` so float fields can propagate.
+/// Because `NaN != NaN`, an instance field holding `NaN` always counts as overridden.
+pub fn proto_update_field_partial_eq<'a, T>(
+    instance_field: &'a T,
+    old_prototype_field: &'a T,
+    new_prototype_field: &'a T,
+) -> &'a T
+where
+    T: PartialEq,
+{
+    if old_prototype_field != instance_field {
+        instance_field
+    } else {
+        new_prototype_field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        proto_update_field, proto_update_field_partial_eq, tests::Person, Library, Record, RecordId,
+    };
+
+    #[test]
+    fn test_locked_mutate() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let proto_id = catalog.create(Person {
+            age: 20,
+            name: String::from("Atom"),
+            fav_food: String::from("Apples"),
+        });
+        let instance_id = catalog.create_from_prototype(proto_id);
+
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|person| person.age = 21)
+            .unwrap();
+
+        assert_eq!(21, catalog.get(proto_id).age);
+        assert_eq!(21, catalog.get(instance_id).age);
+
+        catalog
+            .lock(instance_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Eva"))
+            .unwrap();
+
+        assert_eq!(String::from("Atom"), catalog.get(proto_id).name);
+        assert_eq!(String::from("Eva"), catalog.get(instance_id).name);
+    }
+
+    #[test]
+    fn test_record_id_typing() {
+        let library = Library::default();
+        library.register::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::default());
+
+        let untyped_id = id.untyped();
+        assert_eq!(id.index(), untyped_id.index());
+        assert_eq!(id, untyped_id.typed::<Person>());
+        assert_eq!(RecordId::<Person>::new(id.index()), id);
+        assert_eq!("RecordId(0)", format!("{:?}", untyped_id));
+    }
+
+    #[test]
+    fn test_proto_update_float_fields() {
+        let library = Library::default();
+        library.register::<Position>();
+        let catalog = library.checkout::<Position>();
+        let proto_id = catalog.create(Position { x: 1.0, y: 1.0 });
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let nan_instance_id = catalog.create_from_prototype(proto_id);
+
+        catalog
+            .lock(instance_id)
+            .unwrap()
+            .mutate(|position| position.y = 5.0)
+            .unwrap();
+        catalog
+            .lock(nan_instance_id)
+            .unwrap()
+            .mutate(|position| position.x = f32::NAN)
+            .unwrap();
+        catalog
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|position| {
+                position.x = 2.0;
+                position.y = 2.0;
+            })
+            .unwrap();
+
+        assert_eq!(2.0, catalog.get(instance_id).x);
+        assert_eq!(5.0, catalog.get(instance_id).y);
+        assert!(catalog.get(nan_instance_id).x.is_nan());
+        assert_eq!(2.0, catalog.get(nan_instance_id).y);
+    }
+
+    #[test]
+    fn test_content_eq_default() {
+        let library = Library::default();
+        library.register::<Position>();
+        let catalog = library.checkout::<Position>();
+        let id = catalog.create(Position { x: 1.0, y: 1.0 });
+
+        let locked = catalog.lock(id).unwrap();
+        assert_eq!(
+            Ok(false),
+            catalog.commit_if_changed(&locked, Position { x: 1.0, y: 1.0 })
+        );
+        assert_eq!(
+            Ok(true),
+            catalog.commit_if_changed(&locked, Position { x: 1.0, y: 2.0 })
+        );
+        drop(locked);
+        assert_eq!(2.0, catalog.get(id).y);
+    }
+
+    #[test]
+    fn test_proto_update_in_library() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Badge>();
+        let people = library.checkout::<Person>();
+        let badges = library.checkout::<Badge>();
+        let owner_id = people.create(Person {
+            name: String::from("Atom"),
+            ..Default::default()
+        });
+        let proto_id = badges.create(Badge {
+            owner_id: None,
+            caption: String::from("Visitor"),
+            owner_name: String::default(),
+        });
+        let instance_id = badges.create_from_prototype(proto_id);
+        badges
+            .lock(instance_id)
+            .unwrap()
+            .mutate(|badge| badge.owner_id = Some(owner_id))
+            .unwrap();
+
+        badges
+            .lock(proto_id)
+            .unwrap()
+            .mutate(|badge| badge.caption = String::from("Staff"))
+            .unwrap();
+
+        assert_eq!(String::from("Staff"), badges.get(instance_id).caption);
+        assert_eq!(String::from("Atom"), badges.get(instance_id).owner_name);
+        assert_eq!(String::default(), badges.get(proto_id).owner_name);
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Badge {
+        owner_id: Option<RecordId<Person>>,
+        caption: String,
+        owner_name: String,
+    }
+    impl Record for Badge {
+        fn type_name() -> &'static str {
+            "Badge"
+        }
+
+        fn proto_update(&self, old: &Badge, new: &Badge) -> Badge {
+            Badge {
+                owner_id: *proto_update_field(&self.owner_id, &old.owner_id, &new.owner_id),
+                caption: proto_update_field(&self.caption, &old.caption, &new.caption).clone(),
+                owner_name: self.owner_name.clone(),
+            }
+        }
+
+        fn proto_update_in_library(&self, old: &Badge, new: &Badge, library: &Library) -> Badge {
+            let mut badge = self.proto_update(old, new);
+            let people = library.checkout::<Person>();
+            if let Some(owner) = badge.owner_id.and_then(|id| people.get_checked(id)) {
+                badge.owner_name = owner.name.clone();
+            }
+            badge
+        }
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Position {
+        x: f32,
+        y: f32,
+    }
+    impl Record for Position {
+        fn type_name() -> &'static str {
+            "Position"
+        }
+
+        fn proto_update(&self, old: &Position, new: &Position) -> Position {
+            Position {
+                x: *proto_update_field_partial_eq(&self.x, &old.x, &new.x),
+                y: *proto_update_field_partial_eq(&self.y, &old.y, &new.y),
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// The primitives record locking is built on. Under `--cfg loom` they're swapped for loom's
+// models so the loom tests in `catalog` can explore every interleaving of them:
+//
+//     RUSTFLAGS="--cfg loom" cargo test -p macaw_data --release --lib loom
+//
+// `Arc` stays std's either way since `arc_swap` needs it, so published reads aren't modeled.
+#[cfg(not(loom))]
+pub(crate) use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    thread::{self, ThreadId},
+};
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    sync::{Mutex, MutexGuard},
+    thread::{self, ThreadId},
+};
+
+// loom's `Condvar` lacks the `*_while` helpers, so they're rebuilt on top of `wait`. loom never
+// times out a wait.
+#[cfg(loom)]
+#[derive(Debug, Default)]
+pub(crate) struct Condvar(loom::sync::Condvar);
+
+#[cfg(loom)]
+impl Condvar {
+    pub(crate) fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> std::sync::LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.0.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    pub(crate) fn wait_timeout_while<'a, T, F>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        _timeout: std::time::Duration,
+        condition: F,
+    ) -> std::sync::LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait_while(guard, condition)
+            .map(|guard| (guard, WaitTimeoutResult))
+            .map_err(|error| std::sync::PoisonError::new((error.into_inner(), WaitTimeoutResult)))
+    }
+
+    pub(crate) fn notify_all(&self) {
+        self.0.notify_all();
+    }
+}
+
+#[cfg(loom)]
+pub(crate) struct WaitTimeoutResult;
+
+#[cfg(loom)]
+impl WaitTimeoutResult {
+    pub(crate) fn timed_out(&self) -> bool {
+        false
+    }
+}
@@ -1 +1 @@
-
+pub use crate::undo::{UndoPreview, UndoRedo};
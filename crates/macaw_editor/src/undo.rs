@@ -5,6 +5,13 @@ trait Undoable: Debug {
     fn undo(&mut self, library: &Library);
     fn redo(&mut self, library: &Library);
     fn lsn(&self) -> u64;
+    fn transaction(&self) -> Option<u64>;
+    fn preview(&self, preview: &mut UndoPreview);
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UndoPreview {
+    pub changes: Vec<(&'static str, RecordId)>,
 }
 
 #[derive(Debug)]
@@ -12,10 +19,34 @@ struct UndoRecord<R>
 where
     R: Record,
 {
-    pub record_id: RecordId,
+    pub record_id: RecordId<R>,
     pub old_record: Option<R>,
-    pub new_record: R,
+    pub new_record: Option<R>,
     pub lsn: u64,
+    pub transaction: Option<u64>,
+    // Written by a prototype cascade, so restoring it mustn't turn inherited fields into
+    // overrides.
+    pub inherited: bool,
+}
+
+impl<R> UndoRecord<R>
+where
+    R: Record,
+{
+    fn restore(&self, library: &Library, record: &R) {
+        let catalog = library.checkout::<R>();
+        if catalog.contains(self.record_id) {
+            let lock = catalog.lock(self.record_id).unwrap();
+            match self.inherited {
+                true => catalog.commit_inherited(&lock, record.clone()).unwrap(),
+                false => catalog.commit(&lock, record.clone()).unwrap(),
+            }
+        } else {
+            catalog
+                .create_with_id(self.record_id, record.clone())
+                .unwrap();
+        }
+    }
 }
 
 impl<R> Undoable for UndoRecord<R>
@@ -24,21 +55,30 @@ where
 {
     fn undo(&mut self, library: &Library) {
         if let Some(old_record) = &self.old_record {
-            let catalog = library.checkout::<R>();
-            let lock = catalog.lock(self.record_id);
-            catalog.commit(&lock, old_record.clone());
+            self.restore(library, old_record);
         }
     }
 
     fn redo(&mut self, library: &Library) {
-        let catalog = library.checkout::<R>();
-        let lock = catalog.lock(self.record_id);
-        catalog.commit(&lock, self.new_record.clone());
+        match &self.new_record {
+            Some(new_record) => self.restore(library, new_record),
+            None => library.checkout::<R>().delete(self.record_id),
+        }
     }
 
     fn lsn(&self) -> u64 {
         self.lsn
     }
+
+    fn transaction(&self) -> Option<u64> {
+        self.transaction
+    }
+
+    fn preview(&self, preview: &mut UndoPreview) {
+        preview
+            .changes
+            .push((R::type_name(), self.record_id.untyped()));
+    }
 }
 
 #[derive(Debug)]
@@ -66,6 +106,16 @@ impl Undoable for UndoableBundle {
 
         self.undoables.last().unwrap().lsn()
     }
+
+    fn transaction(&self) -> Option<u64> {
+        None
+    }
+
+    fn preview(&self, preview: &mut UndoPreview) {
+        for undoable in &self.undoables {
+            undoable.preview(preview);
+        }
+    }
 }
 
 trait Watcher {
@@ -77,6 +127,7 @@ where
     R: Record,
 {
     cur_watermark: Watermark,
+    excluded: bool,
     phantom: PhantomData<R>,
 }
 
@@ -84,11 +135,12 @@ impl<R> WatcherState<R>
 where
     R: Record,
 {
-    pub fn new(library: &Library) -> WatcherState<R> {
+    pub fn new(library: &Library, excluded: bool) -> WatcherState<R> {
         let catalog = library.checkout::<R>();
         let cur_watermark = catalog.watermark();
         WatcherState {
             cur_watermark,
+            excluded,
             phantom: Default::default(),
         }
     }
@@ -99,15 +151,22 @@ where
     R: Record,
 {
     fn consume_change_log(&mut self, library: &Library) -> Vec<Box<dyn Undoable>> {
+        if self.excluded {
+            self.advance_watermark(library);
+            return vec![];
+        }
+
         let catalog = library.checkout::<R>();
         let new_watermark = catalog.watermark();
         let mut undoables: Vec<Box<dyn Undoable>> = vec![];
         for change in catalog.changes(self.cur_watermark, new_watermark) {
             undoables.push(Box::from(UndoRecord {
                 record_id: change.record_id(),
-                old_record: change.old_record().cloned(),
-                new_record: change.new_record().clone(),
+                old_record: change.old_record().map(Record::snapshot_for_history),
+                new_record: change.new_record().map(Record::snapshot_for_history),
                 lsn: change.lsn(),
+                transaction: change.transaction(),
+                inherited: change.cause().is_some(),
             }));
         }
 
@@ -165,7 +224,15 @@ impl UndoRedo {
         R: Record,
     {
         self.watchers
-            .push(Box::from(WatcherState::<R>::new(&self.library)));
+            .push(Box::from(WatcherState::<R>::new(&self.library, false)));
+    }
+
+    pub fn watch_excluded<R>(&mut self)
+    where
+        R: Record,
+    {
+        self.watchers
+            .push(Box::from(WatcherState::<R>::new(&self.library, true)));
     }
 
     pub fn undo(&mut self) {
@@ -186,12 +253,45 @@ impl UndoRedo {
         }
     }
 
-    pub fn pause_scope(&mut self) -> PauseScope {
+    pub fn peek_undo(&mut self) -> Option<UndoPreview> {
+        self.consume_change_logs();
+        self.undo_stack
+            .last()
+            .map(|top| Self::preview(top.as_ref()))
+    }
+
+    pub fn peek_redo(&mut self) -> Option<UndoPreview> {
+        self.consume_change_logs();
+        self.redo_stack
+            .last()
+            .map(|top| Self::preview(top.as_ref()))
+    }
+
+    // Groups the top `n` undo steps into one, for edits that should have been made in a
+    // combine scope. Fewer steps are merged if the stack doesn't hold `n`.
+    pub fn merge_last(&mut self, n: usize) {
+        self.consume_change_logs();
+        let merge_start = self.undo_stack.len().saturating_sub(n);
+        if self.undo_stack.len() - merge_start < 2 {
+            return;
+        }
+        let undoables = self.undo_stack.split_off(merge_start);
+        self.undo_stack
+            .push(Box::from(UndoableBundle { undoables }));
+    }
+
+    fn preview(undoable: &dyn Undoable) -> UndoPreview {
+        let mut preview = UndoPreview::default();
+        undoable.preview(&mut preview);
+        preview
+    }
+
+    pub fn pause_scope(&mut self) -> PauseScope<'_> {
         self.consume_change_logs();
         PauseScope { undo_redo: self }
     }
 
-    pub fn combine_scope(&mut self) -> CombineScope {
+    pub fn combine_scope(&mut self) -> CombineScope<'_> {
         self.consume_change_logs();
         CombineScope { undo_redo: self }
     }
@@ -226,15 +326,84 @@ impl UndoRedo {
         }
         undoables.sort_by(|a, b| a.lsn().partial_cmp(&b.lsn()).unwrap());
 
-        undoables
+        Self::group_transactions(undoables)
+    }
+
+    // Changes written by one `Catalog::transaction` are undone as a single step.
+    fn group_transactions(undoables: Vec<Box<dyn Undoable>>) -> Vec<Box<dyn Undoable>> {
+        let mut groups: Vec<Vec<Box<dyn Undoable>>> = vec![];
+        for undoable in undoables {
+            let transaction = undoable.transaction();
+            match groups.last_mut() {
+                Some(group) if transaction.is_some() && group[0].transaction() == transaction => {
+                    group.push(undoable)
+                }
+                _ => groups.push(vec![undoable]),
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|mut group| -> Box<dyn Undoable> {
+                if group.len() == 1 {
+                    group.pop().unwrap()
+                } else {
+                    Box::from(UndoableBundle { undoables: group })
+                }
+            })
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::UndoRedo;
+    use crate::{UndoPreview, UndoRedo};
     use macaw_data::{proto_update_field, Library, Record};
 
+    #[test]
+    fn test_peek_undo_redo() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        undo_redo.watch::<Dog>();
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+        let person_id = person_catalog.create(Person::new(29, String::from("0")));
+        let dog_id = dog_catalog.create(Dog::new(String::from("Pug")));
+        assert_eq!(None, undo_redo.peek_redo());
+
+        {
+            let _combine_scope = undo_redo.combine_scope();
+            person_catalog
+                .lock(person_id)
+                .unwrap()
+                .mutate(|person| person.age = 30)
+                .unwrap();
+            dog_catalog
+                .lock(dog_id)
+                .unwrap()
+                .mutate(|dog| dog.breed = String::from("Corgi"))
+                .unwrap();
+        }
+
+        let preview = UndoPreview {
+            changes: vec![("Person", person_id.untyped()), ("Dog", dog_id.untyped())],
+        };
+        assert_eq!(Some(preview.clone()), undo_redo.peek_undo());
+        assert_eq!(30, person_catalog.get(person_id).age);
+
+        undo_redo.undo();
+        assert_eq!(Some(preview), undo_redo.peek_redo());
+        assert_eq!(
+            Some(UndoPreview {
+                changes: vec![("Dog", dog_id.untyped())],
+            }),
+            undo_redo.peek_undo()
+        );
+    }
+
     #[test]
     fn test_undo_redo() {
         let library = Library::default();
@@ -245,12 +414,9 @@ mod tests {
 
         let id = catalog.create(Person::new(29, String::from("0")));
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("1");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("1"))
+            .unwrap();
 
         assert_eq!(String::from("1"), catalog.get(id).name);
 
@@ -260,12 +426,9 @@ mod tests {
         undo_redo.redo();
         assert_eq!(String::from("1"), catalog.get(id).name);
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("2");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("2"))
+            .unwrap();
 
         undo_redo.undo();
         assert_eq!(String::from("1"), catalog.get(id).name);
@@ -283,24 +446,18 @@ mod tests {
 
         let id = catalog.create(Person::new(29, String::from("0")));
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("1");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("1"))
+            .unwrap();
 
         assert_eq!(String::from("1"), catalog.get(id).name);
 
         undo_redo.undo();
         assert_eq!(String::from("0"), catalog.get(id).name);
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("2");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("2"))
+            .unwrap();
 
         undo_redo.redo();
         assert_eq!(String::from("2"), catalog.get(id).name);
@@ -316,21 +473,18 @@ mod tests {
 
         let id = catalog.create(Person::new(29, String::from("0")));
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("1");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("1"))
+            .unwrap();
 
         assert_eq!(String::from("1"), catalog.get(id).name);
 
         {
             let _pause_scope = undo_redo.pause_scope();
-            let person = catalog.lock(id);
+            let person = catalog.lock(id).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("2");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
         }
 
         undo_redo.undo();
@@ -349,27 +503,24 @@ mod tests {
 
         let id = catalog.create(Person::new(29, String::from("0")));
 
-        {
-            let person = catalog.lock(id);
-            let mut write = person.value.clone();
-            write.name = String::from("1");
-            catalog.commit(&person, write);
-        }
+        catalog
+            .edit(id, |person| person.name = String::from("1"))
+            .unwrap();
 
         assert_eq!(String::from("1"), catalog.get(id).name);
 
         {
             let _combine_scope = undo_redo.combine_scope();
-            let person = catalog.lock(id);
+            let person = catalog.lock(id).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("2");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("3");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
             let mut write = person.value.clone();
             write.name = String::from("4");
-            catalog.commit(&person, write);
+            catalog.commit(&person, write).unwrap();
         }
 
         undo_redo.undo();
@@ -379,6 +530,61 @@ mod tests {
         assert_eq!(String::from("4"), catalog.get(id).name);
     }
 
+    #[test]
+    fn test_merge_last() {
+        let library = Library::default();
+        library.register::<Person>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        let catalog = library.checkout::<Person>();
+        let id = catalog.create(Person::new(29, String::from("0")));
+        for &name in &["1", "2", "3"] {
+            catalog
+                .lock(id)
+                .unwrap()
+                .mutate(|person| person.name = String::from(name))
+                .unwrap();
+        }
+
+        undo_redo.merge_last(2);
+        undo_redo.undo();
+        assert_eq!(String::from("1"), catalog.get(id).name);
+
+        undo_redo.redo();
+        assert_eq!(String::from("3"), catalog.get(id).name);
+
+        undo_redo.undo();
+        undo_redo.undo();
+        assert_eq!(String::from("0"), catalog.get(id).name);
+    }
+
+    #[test]
+    fn test_undo_transaction() {
+        let library = Library::default();
+        library.register::<Person>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        let catalog = library.checkout::<Person>();
+        let parent_id = catalog.create(Person::new(40, String::from("Parent")));
+        let child_id = catalog.create(Person::new(10, String::from("Child")));
+
+        catalog
+            .transaction(&[parent_id, child_id], |people| {
+                for person in people.values_mut() {
+                    person.age += 1;
+                }
+            })
+            .unwrap();
+
+        undo_redo.undo();
+        assert_eq!(40, catalog.get(parent_id).age);
+        assert_eq!(10, catalog.get(child_id).age);
+
+        undo_redo.redo();
+        assert_eq!(41, catalog.get(parent_id).age);
+        assert_eq!(11, catalog.get(child_id).age);
+    }
+
     #[test]
     fn test_multiple_record_type_order() {
         let library = Library::default();
@@ -393,19 +599,13 @@ mod tests {
         let person_id = person_catalog.create(Person::new(29, String::from("Tucker")));
         let dog_id = dog_catalog.create(Dog::new(String::from("Red Heeler")));
 
-        {
-            let dog = dog_catalog.lock(dog_id);
-            let mut write = dog.value.clone();
-            write.breed = String::from("Blue Heeler");
-            dog_catalog.commit(&dog, write);
-        }
+        dog_catalog
+            .edit(dog_id, |dog| dog.breed = String::from("Blue Heeler"))
+            .unwrap();
 
-        {
-            let person = person_catalog.lock(person_id);
-            let mut write = person.value.clone();
-            write.name = String::from("Jim");
-            person_catalog.commit(&person, write);
-        }
+        person_catalog
+            .edit(person_id, |person| person.name = String::from("Jim"))
+            .unwrap();
 
         undo_redo.undo();
 
@@ -418,6 +618,90 @@ mod tests {
         assert_eq!(String::from("Red Heeler"), dog_catalog.get(dog_id).breed);
     }
 
+    #[test]
+    fn test_watch_excluded() {
+        let library = Library::default();
+        library.register::<Person>();
+        library.register::<Dog>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        undo_redo.watch_excluded::<Dog>();
+        let person_catalog = library.checkout::<Person>();
+        let dog_catalog = library.checkout::<Dog>();
+
+        let person_id = person_catalog.create(Person::new(29, String::from("Tucker")));
+        let dog_id = dog_catalog.create(Dog::new(String::from("Red Heeler")));
+
+        person_catalog
+            .lock(person_id)
+            .unwrap()
+            .mutate(|person| person.name = String::from("Jim"))
+            .unwrap();
+        dog_catalog
+            .lock(dog_id)
+            .unwrap()
+            .mutate(|dog| dog.breed = String::from("Blue Heeler"))
+            .unwrap();
+
+        undo_redo.undo();
+        assert_eq!(String::from("Tucker"), person_catalog.get(person_id).name);
+        assert_eq!(String::from("Blue Heeler"), dog_catalog.get(dog_id).breed);
+
+        undo_redo.undo();
+        undo_redo.undo();
+        assert_eq!(String::from("Blue Heeler"), dog_catalog.get(dog_id).breed);
+    }
+
+    #[test]
+    fn test_undo_prototype_edit_keeps_overrides() {
+        let library = Library::default();
+        library.register::<Person>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        let catalog = library.checkout::<Person>();
+
+        let proto_id = catalog.create(Person::new(29, String::from("Proto")));
+        let instance_id = catalog.create_from_prototype(proto_id);
+        let nested_id = catalog.create_from_prototype(instance_id);
+        {
+            let _combine_scope = undo_redo.combine_scope();
+            catalog.edit(proto_id, |person| person.age = 30).unwrap();
+        }
+        assert_eq!(30, catalog.get(nested_id).age);
+
+        undo_redo.undo();
+        assert_eq!(29, catalog.get(instance_id).age);
+        assert_eq!(29, catalog.get(nested_id).age);
+        undo_redo.redo();
+        assert_eq!(30, catalog.get(instance_id).age);
+        assert_eq!(30, catalog.get(nested_id).age);
+        for id in [instance_id, nested_id] {
+            assert!(catalog.overridden_fields(id).is_empty());
+        }
+
+        catalog.edit(proto_id, |person| person.age = 31).unwrap();
+        assert_eq!(31, catalog.get(nested_id).age);
+    }
+
+    #[test]
+    fn test_undo_delete() {
+        let library = Library::default();
+        library.register::<Person>();
+        let mut undo_redo = UndoRedo::new(library.clone());
+        undo_redo.watch::<Person>();
+        let catalog = library.checkout::<Person>();
+
+        let id = catalog.create(Person::new(29, String::from("0")));
+        catalog.delete(id);
+        assert!(!catalog.contains(id));
+
+        undo_redo.undo();
+        assert_eq!(String::from("0"), catalog.get(id).name);
+
+        undo_redo.redo();
+        assert!(!catalog.contains(id));
+    }
+
     #[derive(Clone, Debug, Default)]
     struct Person {
         age: i32,
@@ -434,10 +718,25 @@ mod tests {
         }
 
         fn proto_update(&self, old: &Person, new: &Person) -> Person {
-            return Person {
+            Person {
                 age: *proto_update_field(&self.age, &old.age, &new.age),
                 name: proto_update_field(&self.name, &old.name, &new.name).clone(),
-            };
+            }
+        }
+
+        fn changed_fields(&self, other: &Person) -> Vec<&'static str> {
+            let mut changed_fields = vec![];
+            if self.age != other.age {
+                changed_fields.push("age");
+            }
+            if self.name != other.name {
+                changed_fields.push("name");
+            }
+            changed_fields
+        }
+
+        fn field_names() -> &'static [&'static str] {
+            &["age", "name"]
         }
     }
     #[derive(Clone, Debug, Default)]
@@ -455,9 +754,9 @@ mod tests {
         }
 
         fn proto_update(&self, old: &Dog, new: &Dog) -> Dog {
-            return Dog {
+            Dog {
                 breed: proto_update_field(&self.breed, &old.breed, &new.breed).clone(),
-            };
+            }
         }
     }
 }
@@ -6,12 +6,9 @@ fn main() {
     let place_catalog = library.checkout::<Place>();
     let world_place_id = place_catalog.create(Place::default());
 
-    {
-        let locked_world_place = place_catalog.lock(world_place_id);
-        let mut writable_world_place = locked_world_place.value.clone();
-        writable_world_place.name = String::from("World");
-        place_catalog.commit(&locked_world_place, writable_world_place);
-    }
+    place_catalog
+        .edit(world_place_id, |place| place.name = String::from("World"))
+        .unwrap();
 
     let readonly_world_place = place_catalog.get(world_place_id);
 
@@ -28,8 +25,8 @@ impl Record for Place {
     }
 
     fn proto_update(&self, old: &Self, new: &Self) -> Self {
-        return Place {
+        Place {
             name: proto_update_field(&self.name, &old.name, &new.name).clone(),
-        };
+        }
     }
 }